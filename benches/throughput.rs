@@ -0,0 +1,78 @@
+//! Baselines for query parsing and indexing throughput, run with `cargo bench`
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use std::fs;
+use tika::indexer::{index_files, IndexOptions};
+use tika::xapian_utils::parse_user_query;
+use xapian_rusty::{WritableDatabase, BRASS, DB_CREATE_OR_OVERWRITE};
+
+/// Queries covering the syntax people actually type
+const QUERIES: &[&str] = &[
+    "rust",
+    "embedded search engine",
+    "tag:rust",
+    "author:steve AND tag:tika",
+    r#""trimmed the lamp""#,
+    "lamp NEAR/5 keeper",
+    "(tag:rust OR tag:go) AND NOT draft",
+    "date:202106",
+    "date:2021-01-01..2021-12-31 AND title:notes",
+    "searchabl*",
+];
+
+/// How many notes the indexing benchmark writes
+const DOCS: usize = 200;
+
+fn query_parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_user_query");
+    group.throughput(Throughput::Elements(QUERIES.len() as u64));
+    group.bench_function("corpus", |b| {
+        b.iter(|| {
+            for q in QUERIES {
+                black_box(parse_user_query(black_box(q)).expect("Failed to parse"));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn indexing(c: &mut Criterion) {
+    let dir = tempfile::tempdir().expect("Failed to create tempdir");
+    let notes = dir.path().join("notes");
+    fs::create_dir(&notes).expect("Failed to create dir");
+    for i in 0..DOCS {
+        fs::write(
+            notes.join(format!("note-{}.md", i)),
+            format!(
+                "---\nauthor: Steve Sosik\ndate: 2021-06-22\ntags:\n- bench\n- tag{}\ntitle: Note {}\n---\n# Heading {}\n\n{}\n",
+                i % 10,
+                i,
+                i,
+                "Some representative body text about lighthouses, keepers and lamps. ".repeat(20)
+            ),
+        )
+        .expect("Failed to write note");
+    }
+    let glob = format!("{}/*.md", notes.display());
+    let db_path = dir.path().join("db");
+    let db_path = db_path.to_str().unwrap();
+
+    let mut group = c.benchmark_group("index_files");
+    // Reported as documents per second
+    group.throughput(Throughput::Elements(DOCS as u64));
+    group.sample_size(10);
+    group.bench_function("generated", |b| {
+        b.iter(|| {
+            let mut db = WritableDatabase::new(db_path, BRASS, DB_CREATE_OR_OVERWRITE)
+                .expect("Failed to create database");
+            let paths = glob::glob(&glob).expect("Failed to glob");
+            let stats = index_files(Some(&mut db), paths, "en", &IndexOptions::default())
+                .expect("Failed to index");
+            assert_eq!(DOCS, stats.indexed);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, query_parsing, indexing);
+criterion_main!(benches);
@@ -0,0 +1,1016 @@
+use crate::tika_document::{parse_file, ParseError, TikaDocument};
+use crate::util::{DEFAULT_COMMIT_BATCH_SIZE, DEFAULT_HEADING_WEIGHT};
+use crate::xapian_utils::{
+    custom_field_key_term, custom_field_prefix, exact_tag_term, stemmer, DATE_SLOT, MTIME_SLOT,
+    STEMMER_LANGUAGE_KEY,
+};
+use color_eyre::Report;
+use glob::GlobResult;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{fmt, path::Path, time::Instant};
+use xapian_rusty::{Document, TermGenerator, WritableDatabase, FLAG_SPELLING};
+
+/// Set from a signal handler, e.g. on Ctrl-C, to have `index_files` stop after the current file and
+/// commit what it has indexed so far
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Settings for an `index_files` run
+#[derive(Debug, Clone)]
+pub struct IndexOptions {
+    /// How many times more a term in a Markdown heading counts, see `update_index`
+    pub heading_weight: u32,
+    /// Index notes marked `draft` in their front matter too, rather than skipping them
+    pub include_drafts: bool,
+    /// Redraw a count of the files processed out of those matched in place on stdout as the run
+    /// goes
+    pub progress: bool,
+    /// Commit every this many documents indexed as well as at the end, so a run that's killed
+    /// part way keeps most of its work and a huge collection isn't one huge transaction
+    pub commit_batch_size: usize,
+}
+
+impl Default for IndexOptions {
+    fn default() -> IndexOptions {
+        IndexOptions {
+            heading_weight: DEFAULT_HEADING_WEIGHT,
+            include_drafts: false,
+            progress: false,
+            commit_batch_size: DEFAULT_COMMIT_BATCH_SIZE,
+        }
+    }
+}
+
+/// Index every file in `paths` into `db`, stemming with `lang`, committing in batches as set out
+/// in `opts`. Files that can't be parsed are reported and counted rather than stopping the run.
+/// Without a `db` this is a dry run, the files are only parsed.
+pub fn index_files(
+    mut db: Option<&mut WritableDatabase>,
+    paths: impl Iterator<Item = GlobResult>,
+    lang: &str,
+    opts: &IndexOptions,
+) -> Result<IndexStats, Report> {
+    let start = Instant::now();
+    let mut stats = IndexStats::default();
+
+    let mut tg = TermGenerator::new()?;
+    let mut stemmer = stemmer(lang)?;
+    tg.set_stemmer(&mut stemmer)?;
+    // Gather spelling data for query corrections, see `spelling_suggestion`
+    if let Some(db) = db.as_deref_mut() {
+        // Queries need to stem with the same language, remember which one was used. Set up front
+        // so it's part of every batch committed.
+        db.set_metadata(STEMMER_LANGUAGE_KEY, lang)?;
+        tg.set_database(db)?;
+        tg.set_flags(FLAG_SPELLING)?;
+    }
+
+    // Glob up front for the total to show progress against
+    let paths: Vec<GlobResult> = paths.collect();
+    let total = paths.len();
+
+    // TODO is there a rustier way to do this?
+    for entry in paths {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            stats.interrupted = true;
+            break;
+        }
+        stats.matched += 1;
+        if opts.progress {
+            print!("\rIndexing {}/{} files", stats.matched, total);
+            io::stdout().flush()?;
+        }
+        match entry {
+            // TODO convert this to iterator style using map/filter
+            Ok(path) => {
+                if !path.is_file() {
+                    stats.skipped += 1;
+                    continue;
+                }
+                match parse_file(&path) {
+                    Ok(tikadoc) if tikadoc.draft && !opts.include_drafts => {
+                        stats.skipped += 1;
+                        info!("Skipping draft {}", tikadoc.filename);
+                    }
+                    Ok(tikadoc) => {
+                        if tikadoc.full_path.to_str().is_none() {
+                            warn!(
+                                "{} isn't valid UTF-8, indexing it with the invalid bytes replaced",
+                                path.display()
+                            );
+                        }
+                        if let Err(e) = tikadoc.parse_date() {
+                            warn!("{}, indexing it without a date", e);
+                        }
+                        if let Some(db) = db.as_deref_mut() {
+                            update_index(db, &mut tg, &tikadoc, opts.heading_weight)?;
+                            if (stats.indexed + 1) % opts.commit_batch_size.max(1) == 0 {
+                                db.commit()?;
+                                stats.commits += 1;
+                                debug!("Committed after {} documents", stats.indexed + 1);
+                            }
+                        }
+                        stats.indexed += 1;
+                        info!("Indexed {}", tikadoc.filename);
+                    }
+                    // Markdown files without front matter, e.g. a README, aren't notes
+                    Err(e @ ParseError::NoFrontMatter { .. }) => {
+                        stats.skipped += 1;
+                        warn!("{}, skipping it", e);
+                    }
+                    Err(e) => {
+                        // The error names the file and what about it couldn't be parsed
+                        stats.failed += 1;
+                        error!("{}", e);
+                    }
+                }
+            }
+
+            Err(e) => {
+                stats.failed += 1;
+                error!("{}", e)
+            }
+        }
+    }
+    if opts.progress {
+        // Clear the counter so the summary starts on a clean line
+        print!("\r{}", termion::clear::CurrentLine);
+        io::stdout().flush()?;
+    }
+
+    if let Some(db) = db {
+        db.commit()?;
+        stats.commits += 1;
+    }
+
+    stats.elapsed_secs = start.elapsed().as_secs_f64();
+    Ok(stats)
+}
+
+/// Store each group of synonyms in `db`, as (term, synonyms) pairs, so a query for any of the words
+/// also matches the others. Synonyms of a word removed from the config linger until a reindex.
+pub fn add_synonyms(
+    db: &mut WritableDatabase,
+    groups: &[(String, Vec<String>)],
+) -> Result<(), Report> {
+    for (term, synonyms) in groups {
+        let words: Vec<String> = std::iter::once(term)
+            .chain(synonyms)
+            .map(|w| w.to_lowercase())
+            .collect();
+        for word in &words {
+            for synonym in words.iter().filter(|s| *s != word) {
+                db.add_synonym(word, synonym)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Counters accumulated over an indexing run
+#[derive(Debug, Default, Serialize)]
+pub struct IndexStats {
+    /// Paths matched by the source glob
+    pub matched: usize,
+    pub indexed: usize,
+    /// Matched paths that aren't regular files, e.g. directories, drafts, and files without front
+    /// matter
+    pub skipped: usize,
+    pub failed: usize,
+    pub elapsed_secs: f64,
+    /// Commits to the database, one per `commit_batch_size` documents plus one at the end
+    pub commits: usize,
+    /// The run was cut short by `INTERRUPTED`, the files indexed before then were still committed
+    pub interrupted: bool,
+}
+
+impl fmt::Display for IndexStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Matched {} files: {} indexed, {} skipped, {} failed in {:.2}s",
+            self.matched, self.indexed, self.skipped, self.failed, self.elapsed_secs
+        )?;
+        if self.interrupted {
+            write!(f, ", interrupted")?;
+        }
+        Ok(())
+    }
+}
+
+/// The words of a filename for indexing, without the extension and split on anything that isn't
+/// alphanumeric, e.g. `2021-06-22 meeting notes` for `2021-06-22-meeting-notes.md`. Xapian keeps
+/// underscores within words and may not split on dashes the way you'd expect.
+fn filename_words(filename: &str) -> String {
+    let stem = Path::new(filename)
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+    stem.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Add or replace the document for `tikadoc`, terms are generated with `tg`. Terms in Markdown
+/// headings count `heading_weight` times more than those in the rest of the body.
+pub fn update_index(
+    db: &mut WritableDatabase,
+    tg: &mut TermGenerator,
+    tikadoc: &TikaDocument,
+    heading_weight: u32,
+) -> Result<(), Report> {
+    // Create a new Xapian Document to store attributes on the passed-in TikaDocument
+    let mut doc = Document::new()?;
+    tg.set_document(&mut doc)?;
+
+    tg.index_text_with_prefix(&tikadoc.author, "A")?;
+    // A date that can't be parsed is left out rather than failing the whole document
+    if let Ok(date) = tikadoc.date_str() {
+        tg.index_text_with_prefix(&date, "D")?;
+    }
+    tg.index_text_with_prefix(&tikadoc.filename, "F")?;
+    tg.index_text_with_prefix(&filename_words(&tikadoc.filename), "F")?;
+    tg.index_text_with_prefix(&tikadoc.full_path.to_string_lossy(), "F")?;
+    tg.index_text_with_prefix(&tikadoc.title, "S")?;
+    tg.index_text_with_prefix(&tikadoc.subtitle, "XS")?;
+    for tag in &tikadoc.tags {
+        tg.index_text_with_prefix(&tag, "K")?;
+        doc.add_boolean_term(&exact_tag_term(tag))?;
+    }
+    for link in &tikadoc.links {
+        tg.index_text_with_prefix(&link, "XL")?;
+    }
+    for (key, value) in &tikadoc.extra {
+        tg.index_text_with_prefix(&value, &custom_field_prefix(key))?;
+        // So queries know `key:` is a field, see `xapian_utils::indexed_custom_fields`
+        doc.add_boolean_term(&custom_field_key_term(key))?;
+    }
+
+    // Index the visible text, the original Markdown is kept in the document data for display
+    tg.index_text(&tikadoc.plain_body())?;
+    // Headings are already part of the body, so only the extra weight is added here
+    if heading_weight > 1 {
+        tg.index_text_with_wdf(&tikadoc.headings(), heading_weight - 1)?;
+    }
+
+    // Store the date as a sortable value to support date range queries
+    if let Ok(date) = tikadoc.date_str() {
+        doc.add_value(DATE_SLOT, &date)?;
+    }
+    // Zero-pad the mtime so values sort lexicographically
+    doc.add_value(MTIME_SLOT, &format!("{:020}", tikadoc.mtime))?;
+
+    // Convert the TikaDocument into JSON and set it in the DB for retrieval later
+    doc.set_data(&serde_json::to_string(&tikadoc).unwrap())?;
+
+    let id = "Q".to_owned() + &tikadoc.filename;
+    doc.add_boolean_term(&id)?;
+    db.replace_document(&id, &mut doc)?;
+
+    Ok(())
+}
+
+/// Remove the document indexed from `filename`, which may also be given as a path. Returns
+/// whether a document was actually found and removed.
+pub fn delete_from_index(db: &mut WritableDatabase, filename: &str) -> Result<bool, Report> {
+    let filename = Path::new(filename)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or(filename);
+
+    // Documents are identified by the same boolean term `update_index` uses
+    let id = "Q".to_owned() + filename;
+    let before = db.get_doccount()?;
+    db.delete_document(&id)?;
+    Ok(db.get_doccount()? < before)
+}
+
+#[cfg(test)]
+mod update_index_tests {
+    use super::*;
+    use crate::util::{DEFAULT_HEADING_WEIGHT, DEFAULT_RESULT_LIMIT};
+    use crate::xapian_utils::{
+        backlinks_query, count_matches, facets, filename_query, open_db, parse_user_query,
+        parse_user_query_with_flags, query_db, SortOrder, XapianTag,
+    };
+    use crate::xapian_utils::{DEFAULT_OPERATOR, DEFAULT_QUERY_FLAGS};
+    use std::collections::HashMap;
+    use std::ffi::{OsStr, OsString};
+    use std::fs;
+    use xapian_rusty::FeatureFlag::FlagPartial;
+    use xapian_rusty::{BRASS, DB_CREATE_OR_OPEN, DB_OPEN};
+
+    /// A temporary directory for notes, with the index in its `db` subdirectory
+    struct Fixture {
+        dir: tempfile::TempDir,
+        db_path: String,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let dir = tempfile::tempdir().expect("Failed to create tempdir");
+            let db_path = dir.path().join("db").to_str().unwrap().to_string();
+            Fixture { dir, db_path }
+        }
+
+        fn db_path(&self) -> &str {
+            &self.db_path
+        }
+
+        fn write_note(&self, name: impl AsRef<OsStr>, contents: &str) {
+            fs::write(self.dir.path().join(name.as_ref()), contents).expect("Failed to write note");
+        }
+
+        /// The notes written so far, as `index_files` takes them
+        fn notes(&self) -> glob::Paths {
+            glob::glob(&format!("{}/*.md", self.dir.path().display())).expect("Failed to glob")
+        }
+    }
+
+    fn example_doc() -> TikaDocument {
+        TikaDocument {
+            filename: String::from("example.md"),
+            full_path: OsString::from("/notes/example.md"),
+            mtime: 1624380496,
+            author: String::from("Steve Sosik"),
+            date: String::from("2021-06-22T12:48:16-0400"),
+            tags: vec![String::from("tika")],
+            title: String::from("This is an example note"),
+            subtitle: String::new(),
+            draft: false,
+            links: Vec::new(),
+            body: String::from("Some searchable note body"),
+            extra: HashMap::new(),
+        }
+    }
+
+    fn dated_doc(filename: &str, date: &str) -> TikaDocument {
+        TikaDocument {
+            filename: String::from(filename),
+            date: String::from(date),
+            ..example_doc()
+        }
+    }
+
+    fn index_docs(db_path: &str, docs: &[TikaDocument]) {
+        let mut db = WritableDatabase::new(db_path, BRASS, DB_CREATE_OR_OPEN)
+            .expect("Failed to create database");
+        let mut tg = TermGenerator::new().expect("Failed to create termgenerator");
+        let mut stemmer = stemmer("en").expect("Failed to create stemmer");
+        tg.set_stemmer(&mut stemmer).expect("Failed to set stemmer");
+        for doc in docs {
+            update_index(&mut db, &mut tg, doc, DEFAULT_HEADING_WEIGHT)
+                .expect("Failed to index document");
+        }
+        db.commit().expect("Failed to commit");
+    }
+
+    fn query_filenames(db_path: &str, qstr: &str) -> Vec<String> {
+        let mut db = open_db(db_path).expect("Failed to open database");
+        // With the database, for the custom fields indexed in it
+        let query = parse_user_query_with_flags(
+            qstr,
+            "en",
+            DEFAULT_QUERY_FLAGS,
+            DEFAULT_OPERATOR,
+            Some(&mut db),
+        )
+        .expect("Failed to parse");
+        query_db(&mut db, query, SortOrder::Relevance, DEFAULT_RESULT_LIMIT)
+            .expect("Failed to query")
+            .matches
+            .into_iter()
+            .map(|m| m.doc.filename)
+            .collect()
+    }
+
+    #[test]
+    fn words_of_filenames() {
+        assert_eq!(
+            "2021 06 22 meeting notes",
+            filename_words("2021-06-22-meeting-notes.md")
+        );
+        assert_eq!(
+            "project plan draft",
+            filename_words("project_plan_draft.md")
+        );
+        assert_eq!("README", filename_words("README"));
+    }
+
+    #[test]
+    fn query_filename_words() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let named = |filename: &str| TikaDocument {
+            full_path: OsString::from(format!("/n/{}", filename)),
+            ..dated_doc(filename, "2021-06-22")
+        };
+        index_docs(
+            db_path,
+            &[
+                named("2021-06-22-meeting-notes.md"),
+                named("project_plan_draft.md"),
+            ],
+        );
+
+        for word in &["meeting", "notes"] {
+            assert_eq!(
+                vec!["2021-06-22-meeting-notes.md"],
+                query_filenames(db_path, &format!("filename:{}", word))
+            );
+        }
+        for word in &["project", "plan", "draft"] {
+            assert_eq!(
+                vec!["project_plan_draft.md"],
+                query_filenames(db_path, &format!("filename:{}", word))
+            );
+        }
+
+        // The start of a filename, as typed in the TUI's filename mode
+        let mut db = open_db(db_path).expect("Failed to open database");
+        let mut starting = |fragment: &str| -> Vec<String> {
+            let query = filename_query(fragment, "en", &mut db).expect("Failed to parse");
+            query_db(&mut db, query, SortOrder::Relevance, DEFAULT_RESULT_LIMIT)
+                .expect("Failed to query")
+                .matches
+                .into_iter()
+                .map(|m| m.doc.filename)
+                .collect()
+        };
+        assert_eq!(vec!["project_plan_draft.md"], starting("proj"));
+        assert_eq!(vec!["2021-06-22-meeting-notes.md"], starting("meet"));
+        assert!(starting("zzz").is_empty());
+    }
+
+    #[test]
+    fn query_leaves_index_intact() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        index_docs(db_path, &[example_doc()]);
+
+        // Run the same query twice, the first query must not have clobbered the index
+        for _ in 0..2 {
+            assert_eq!(vec!["example.md"], query_filenames(db_path, "searchable"));
+        }
+    }
+
+    #[test]
+    fn missing_index() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+
+        let err = open_db(db_path).err().expect("Opened a missing index");
+        assert!(err.to_string().contains(db_path));
+        // Nothing is created in its place
+        assert!(!Path::new(db_path).exists());
+    }
+
+    #[test]
+    fn query_prefixed_fields() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        index_docs(db_path, &[example_doc()]);
+
+        assert_eq!(vec!["example.md"], query_filenames(db_path, "author:steve"));
+        assert_eq!(
+            vec!["example.md"],
+            query_filenames(db_path, "tag:tika AND author:steve")
+        );
+        assert!(query_filenames(db_path, "author:bob").is_empty());
+    }
+
+    #[test]
+    fn query_custom_fields() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let mut extra = HashMap::new();
+        extra.insert(String::from("project"), String::from("tika"));
+        extra.insert(String::from("status"), String::from("in progress"));
+        extra.insert(String::from("10"), String::from("30"));
+        let custom = TikaDocument {
+            filename: String::from("custom.md"),
+            extra,
+            ..example_doc()
+        };
+        let meeting = TikaDocument {
+            filename: String::from("meeting.md"),
+            body: String::from("Notes from the meeting"),
+            ..example_doc()
+        };
+        index_docs(db_path, &[example_doc(), custom, meeting]);
+
+        assert_eq!(vec!["custom.md"], query_filenames(db_path, "project:tika"));
+        assert_eq!(
+            vec!["custom.md"],
+            query_filenames(db_path, r#"status:"in progress""#)
+        );
+        assert!(query_filenames(db_path, "status:tika").is_empty());
+
+        // Only indexed keys starting with a letter are fields, a time is just text
+        assert_eq!(
+            vec!["meeting.md"],
+            query_filenames(db_path, "meeting 10:30")
+        );
+        assert_eq!(
+            vec!["meeting.md"],
+            query_filenames(db_path, "meeting unknown:field")
+        );
+    }
+
+    #[test]
+    fn synonyms() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let k8s = TikaDocument {
+            filename: String::from("k8s.md"),
+            body: String::from("Notes on Kubernetes clusters"),
+            ..example_doc()
+        };
+        index_docs(db_path, &[example_doc(), k8s]);
+        let mut db =
+            WritableDatabase::new(db_path, BRASS, DB_OPEN).expect("Failed to open database");
+        add_synonyms(
+            &mut db,
+            &[(String::from("Kubernetes"), vec![String::from("k8s")])],
+        )
+        .expect("Failed to add synonyms");
+        db.commit().expect("Failed to commit");
+
+        let mut db = open_db(db_path).expect("Failed to open database");
+        // A word matched as a prefix, as the last one is with FlagPartial, has no synonyms
+        let flags = DEFAULT_QUERY_FLAGS & !(FlagPartial as i16);
+        let query =
+            parse_user_query_with_flags("k8s", "en", flags, DEFAULT_OPERATOR, Some(&mut db))
+                .expect("Failed to parse");
+        let found: Vec<String> = query_db(&mut db, query, SortOrder::Relevance, 0)
+            .expect("Failed to query")
+            .matches
+            .into_iter()
+            .map(|m| m.doc.filename)
+            .collect();
+        assert_eq!(vec!["k8s.md"], found);
+    }
+
+    #[test]
+    fn query_exact_tags() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let tagged = |filename: &str, tags: &[&str], body: &str| TikaDocument {
+            filename: String::from(filename),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            body: String::from(body),
+            ..example_doc()
+        };
+        index_docs(
+            db_path,
+            &[
+                tagged("go.md", &["Go", "C"], "Goroutines and channels"),
+                tagged("going.md", &["travel"], "Where I'm going, and a c note"),
+                tagged("spaced.md", &["machine learning"], "Models"),
+                tagged("rust.md", &["Rust"], "Ownership"),
+            ],
+        );
+
+        assert_eq!(vec!["go.md"], query_filenames(db_path, "tag:Go"));
+        assert_eq!(vec!["go.md"], query_filenames(db_path, "tag:C"));
+        // Whole tags only, but whatever the case
+        assert_eq!(vec!["go.md"], query_filenames(db_path, "tag:go"));
+        assert_eq!(vec!["go.md"], query_filenames(db_path, "tag:GO"));
+        assert!(query_filenames(db_path, "tag:going").is_empty());
+        assert!(query_filenames(db_path, "tag:machine").is_empty());
+        assert_eq!(vec!["rust.md"], query_filenames(db_path, "tag:rust"));
+        assert_eq!(
+            vec!["spaced.md"],
+            query_filenames(db_path, r#"tag:"machine learning""#)
+        );
+    }
+
+    #[test]
+    fn query_subtitle() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let subtitled = TikaDocument {
+            filename: String::from("subtitled.md"),
+            subtitle: String::from("A closer look at lighthouses"),
+            ..example_doc()
+        };
+        index_docs(db_path, &[example_doc(), subtitled]);
+
+        assert_eq!(
+            vec!["subtitled.md"],
+            query_filenames(db_path, "subtitle:lighthouses")
+        );
+        assert_eq!(
+            vec!["subtitled.md"],
+            query_filenames(db_path, r#"subtitle:"closer look""#)
+        );
+        // Only under the subtitle prefix
+        assert!(query_filenames(db_path, "title:lighthouses").is_empty());
+    }
+
+    #[test]
+    fn query_date_range() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        index_docs(
+            db_path,
+            &[
+                dated_doc("2020.md", "2020-12-31T12:00:00+00:00"),
+                dated_doc("2021.md", "2021-06-22T12:48:16-0400"),
+                dated_doc("2022.md", "2022-01-01T12:00:00+00:00"),
+            ],
+        );
+
+        assert_eq!(
+            vec!["2021.md"],
+            query_filenames(db_path, "date:2021-01-01..2021-12-31")
+        );
+        let mut both = query_filenames(db_path, "date:2020-12-31..2021-12-31");
+        both.sort();
+        assert_eq!(vec!["2020.md", "2021.md"], both);
+    }
+
+    #[test]
+    fn query_date_prefix() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        index_docs(
+            db_path,
+            &[
+                dated_doc("may.md", "2021-05-31T12:00:00+00:00"),
+                dated_doc("june.md", "2021-06-22T12:48:16-0400"),
+                dated_doc("july.md", "2021-07-01T12:00:00+00:00"),
+                dated_doc("2022.md", "2022-06-22T12:00:00+00:00"),
+            ],
+        );
+
+        assert_eq!(vec!["june.md"], query_filenames(db_path, "date:20210622"));
+        assert_eq!(vec!["june.md"], query_filenames(db_path, "date:202106"));
+        let mut year = query_filenames(db_path, "date:2021");
+        year.sort();
+        assert_eq!(vec!["july.md", "june.md", "may.md"], year);
+        assert!(query_filenames(db_path, "date:202108").is_empty());
+    }
+
+    #[test]
+    fn undated_document() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        index_docs(
+            db_path,
+            &[
+                dated_doc("undated.md", "last tuesday"),
+                dated_doc("2021.md", "June 22, 2021"),
+            ],
+        );
+
+        // Still indexed, just left out of date ranges
+        assert_eq!(
+            vec!["undated.md"],
+            query_filenames(db_path, "filename:undated")
+        );
+        assert_eq!(
+            vec!["2021.md"],
+            query_filenames(db_path, "date:2021-01-01..2021-12-31")
+        );
+    }
+
+    #[test]
+    fn query_sorted_by_mtime() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let docs: Vec<TikaDocument> = [("old.md", 1), ("newest.md", 300), ("middle.md", 20)]
+            .iter()
+            .map(|(filename, mtime)| TikaDocument {
+                filename: String::from(*filename),
+                mtime: *mtime,
+                ..example_doc()
+            })
+            .collect();
+        index_docs(db_path, &docs);
+
+        let mut db = open_db(db_path).expect("Failed to open database");
+        let query = parse_user_query("searchable").expect("Failed to parse");
+        let filenames: Vec<String> =
+            query_db(&mut db, query, SortOrder::Mtime, DEFAULT_RESULT_LIMIT)
+                .expect("Failed to query")
+                .matches
+                .into_iter()
+                .map(|m| m.doc.filename)
+                .collect();
+        assert_eq!(vec!["newest.md", "middle.md", "old.md"], filenames);
+    }
+
+    #[test]
+    fn query_ranks_and_percent() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let mut other = dated_doc("other.md", "2021-06-22T12:48:16-0400");
+        other.body = String::from("Another note body");
+        index_docs(db_path, &[example_doc(), other]);
+
+        let mut db = open_db(db_path).expect("Failed to open database");
+        let query = parse_user_query("searchable OR note").expect("Failed to parse");
+        let matches = query_db(&mut db, query, SortOrder::Relevance, DEFAULT_RESULT_LIMIT)
+            .expect("Failed to query")
+            .matches;
+        assert_eq!(
+            vec![1, 2],
+            matches.iter().map(|m| m.rank).collect::<Vec<_>>()
+        );
+        assert_eq!("example.md", matches[0].doc.filename);
+        assert!(matches[0].percent >= matches[1].percent);
+        assert!(matches.iter().all(|m| m.percent > 0 && m.percent <= 100));
+    }
+
+    #[test]
+    fn count() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let docs: Vec<TikaDocument> = (0..30)
+            .map(|i| dated_doc(&format!("{}.md", i), "2021-06-22T12:48:16-0400"))
+            .collect();
+        index_docs(db_path, &docs);
+
+        let mut db = open_db(db_path).expect("Failed to open database");
+        let mut count = |qstr, exact| {
+            let query = parse_user_query(qstr).expect("Failed to parse");
+            count_matches(&mut db, query, exact).expect("Failed to count")
+        };
+        assert_eq!(30, count("searchable", true));
+        assert_eq!(0, count("nonexistent", true));
+        assert_eq!(0, count("nonexistent", false));
+    }
+
+    #[test]
+    fn query_limit() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let docs: Vec<TikaDocument> = (0..150)
+            .map(|i| dated_doc(&format!("{}.md", i), "2021-06-22T12:48:16-0400"))
+            .collect();
+        index_docs(db_path, &docs);
+
+        let mut db = open_db(db_path).expect("Failed to open database");
+        for (limit, expected) in &[(10, 10), (DEFAULT_RESULT_LIMIT, 100), (0, 150)] {
+            let query = parse_user_query("searchable").expect("Failed to parse");
+            let page =
+                query_db(&mut db, query, SortOrder::Relevance, *limit).expect("Failed to query");
+            assert_eq!(*expected, page.matches.len());
+            assert_eq!(*expected, page.matches.last().unwrap().rank);
+            // With this few documents Xapian's estimate is exact, whatever the limit
+            assert_eq!(150, page.estimated);
+        }
+    }
+
+    #[test]
+    fn list_tags() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let tagged = |filename: &str, tags: &[&str]| TikaDocument {
+            filename: String::from(filename),
+            tags: tags.iter().map(|t| String::from(*t)).collect(),
+            ..example_doc()
+        };
+        index_docs(
+            db_path,
+            &[
+                tagged("a.md", &["rust", "tika"]),
+                tagged("b.md", &["Rust"]),
+                tagged("c.md", &["xapian", "machine learning"]),
+            ],
+        );
+
+        let mut db = open_db(db_path).expect("Failed to open database");
+        let tags: Vec<(String, usize)> = facets(&mut db, XapianTag::Tag)
+            .expect("Failed to list tags")
+            .into_iter()
+            .map(|f| (f.value, f.count))
+            .collect();
+        assert_eq!(
+            vec![
+                (String::from("machine learning"), 1),
+                (String::from("rust"), 2),
+                (String::from("tika"), 1),
+                (String::from("xapian"), 1)
+            ],
+            tags
+        );
+        // Every listed tag finds the notes it was counted for
+        for (tag, count) in tags {
+            let found = query_filenames(db_path, &format!("tag:\"{}\"", tag));
+            assert_eq!(count, found.len(), "tag:\"{}\"", tag);
+        }
+
+        // Multi-word fields are listed a word at a time
+        let authors: Vec<String> = facets(&mut db, XapianTag::Author)
+            .expect("Failed to list authors")
+            .into_iter()
+            .map(|f| f.value)
+            .collect();
+        assert_eq!(vec!["sosik", "steve"], authors);
+    }
+
+    #[test]
+    fn markdown_syntax_not_indexed() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let doc = TikaDocument {
+            body: String::from("# Foo\n\nSee [the docs](https://example.com/xyzzy)\n"),
+            ..example_doc()
+        };
+        index_docs(db_path, &[doc]);
+
+        assert_eq!(vec!["example.md"], query_filenames(db_path, "foo"));
+        assert_eq!(vec!["example.md"], query_filenames(db_path, "docs"));
+        assert!(query_filenames(db_path, "xyzzy").is_empty());
+
+        // The stored body is still the original Markdown
+        let mut db = open_db(db_path).expect("Failed to open database");
+        let query = parse_user_query("foo").expect("Failed to parse");
+        let matches = query_db(&mut db, query, SortOrder::Relevance, DEFAULT_RESULT_LIMIT)
+            .expect("Failed to query")
+            .matches;
+        assert!(matches[0].doc.body.starts_with("# Foo"));
+    }
+
+    #[test]
+    fn headings_rank_higher() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let with_body = |filename: &str, body: &str| TikaDocument {
+            filename: String::from(filename),
+            body: String::from(body),
+            ..example_doc()
+        };
+        index_docs(
+            db_path,
+            &[
+                with_body("prose.md", "# Notes\n\nSome words about rust\n"),
+                with_body("heading.md", "# Rust\n\nSome words about notes\n"),
+            ],
+        );
+
+        assert_eq!(
+            vec!["heading.md", "prose.md"],
+            query_filenames(db_path, "rust")
+        );
+    }
+
+    #[test]
+    fn query_links() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        let linking = TikaDocument {
+            filename: String::from("linking.md"),
+            links: vec![String::from("Some Note")],
+            ..example_doc()
+        };
+        index_docs(db_path, &[example_doc(), linking]);
+
+        assert_eq!(
+            vec!["linking.md"],
+            query_filenames(db_path, r#"links:"Some Note""#)
+        );
+        assert!(query_filenames(db_path, r#"links:"Other Note""#).is_empty());
+
+        // Backlinks to either name of a note
+        let backlinks = |targets: &[&str]| -> Vec<String> {
+            let targets: Vec<String> = targets.iter().map(|t| t.to_string()).collect();
+            let mut db = open_db(db_path).expect("Failed to open database");
+            let query = backlinks_query(&targets).expect("Failed to build query");
+            query_db(&mut db, query, SortOrder::Relevance, DEFAULT_RESULT_LIMIT)
+                .expect("Failed to query")
+                .matches
+                .into_iter()
+                .map(|m| m.doc.filename)
+                .collect()
+        };
+        assert_eq!(vec!["linking.md"], backlinks(&["Some Note", "some-note"]));
+        assert_eq!(vec!["linking.md"], backlinks(&["some-note", "Some Note"]));
+        assert!(backlinks(&["Some Other Note"]).is_empty());
+        assert!(backlinks_query(&[]).is_err());
+    }
+
+    #[test]
+    fn dry_run() {
+        let fixture = Fixture::new();
+        fixture.write_note(
+            "good.md",
+            "---\ndate: 2021-06-22\ntags: tika\ntitle: Good\n---\n",
+        );
+        fixture.write_note("bad.md", "No front matter\n");
+
+        let stats = index_files(None, fixture.notes(), "en", &IndexOptions::default())
+            .expect("Failed to parse files");
+        // A file without front matter is skipped rather than failed
+        assert_eq!(
+            (2, 1, 1, 0),
+            (stats.matched, stats.indexed, stats.skipped, stats.failed)
+        );
+    }
+
+    #[test]
+    fn drafts_skipped() {
+        let fixture = Fixture::new();
+        for (name, draft) in &[("final", false), ("draft", true)] {
+            fixture.write_note(
+                format!("{}.md", name),
+                &format!(
+                    "---\ndate: 2021-06-22\ntags: tika\ntitle: Note\ndraft: {}\n---\nSame body\n",
+                    draft
+                ),
+            );
+        }
+        let db_path = fixture.db_path();
+        let index = |include_drafts| {
+            let mut db = WritableDatabase::new(db_path, BRASS, DB_CREATE_OR_OPEN)
+                .expect("Failed to create database");
+            let opts = IndexOptions {
+                include_drafts,
+                ..IndexOptions::default()
+            };
+            index_files(Some(&mut db), fixture.notes(), "en", &opts).expect("Failed to index")
+        };
+
+        let stats = index(false);
+        assert_eq!((1, 1), (stats.indexed, stats.skipped));
+        assert_eq!(vec!["final.md"], query_filenames(db_path, "same body"));
+
+        index(true);
+        let mut both = query_filenames(db_path, "same body");
+        both.sort();
+        assert_eq!(vec!["draft.md", "final.md"], both);
+    }
+
+    #[test]
+    fn batched_commits() {
+        let fixture = Fixture::new();
+        for name in &["one", "two", "three"] {
+            fixture.write_note(
+                format!("{}.md", name),
+                "---\ndate: 2021-06-22\ntags: tika\ntitle: Note\n---\nBatched body\n",
+            );
+        }
+        let db_path = fixture.db_path();
+        let mut db = WritableDatabase::new(db_path, BRASS, DB_CREATE_OR_OPEN)
+            .expect("Failed to create database");
+        let opts = IndexOptions {
+            commit_batch_size: 2,
+            ..IndexOptions::default()
+        };
+
+        let stats =
+            index_files(Some(&mut db), fixture.notes(), "en", &opts).expect("Failed to index");
+        // One batch of two, then the remaining document at the end
+        assert_eq!((3, 2), (stats.indexed, stats.commits));
+        assert_eq!(3, query_filenames(db_path, "batched").len());
+    }
+
+    #[test]
+    fn non_utf8_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let fixture = Fixture::new();
+        fixture.write_note(
+            OsStr::from_bytes(b"caf\xe9.md"),
+            "---\ndate: 2021-06-22\ntags: tika\ntitle: Odd name\n---\nStill indexed\n",
+        );
+        let db_path = fixture.db_path();
+        let mut db = WritableDatabase::new(db_path, BRASS, DB_CREATE_OR_OPEN)
+            .expect("Failed to create database");
+
+        let stats = index_files(
+            Some(&mut db),
+            fixture.notes(),
+            "en",
+            &IndexOptions::default(),
+        )
+        .expect("Failed to index");
+        assert_eq!(1, stats.indexed);
+        assert_eq!(
+            vec!["caf\u{fffd}.md"],
+            query_filenames(db_path, "still indexed")
+        );
+    }
+
+    #[test]
+    fn delete_document() {
+        let fixture = Fixture::new();
+        let db_path = fixture.db_path();
+        index_docs(db_path, &[example_doc()]);
+
+        {
+            let mut db =
+                WritableDatabase::new(db_path, BRASS, DB_OPEN).expect("Failed to open database");
+            assert!(!delete_from_index(&mut db, "missing.md").expect("Failed to delete"));
+            assert!(delete_from_index(&mut db, "/notes/example.md").expect("Failed to delete"));
+            db.commit().expect("Failed to commit");
+        }
+
+        assert!(query_filenames(db_path, "searchable").is_empty());
+    }
+}
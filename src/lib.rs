@@ -0,0 +1,82 @@
+//! Things I Know About: index Markdown + FrontMatter notes into Xapian and query them.
+//!
+//! `index_path` and `search` cover the common cases, the modules expose the pieces they're built
+//! from for finer control.
+
+pub mod indexer;
+pub mod tika_document;
+pub mod tui_app;
+pub mod util;
+pub mod xapian_utils;
+
+pub use crate::tika_document::TikaDocument;
+
+use crate::util::{DEFAULT_RESULT_LIMIT, DEFAULT_STEMMER_LANGUAGE};
+use crate::xapian_utils::{open_db, parse_user_query, query_db, SortOrder};
+use color_eyre::Report;
+use xapian_rusty::{WritableDatabase, BRASS, DB_CREATE_OR_OPEN};
+
+/// Index every Markdown file matching `glob` into the database at `db_path`, creating the
+/// database if needed. Returns how many documents were indexed.
+pub fn index_path(db_path: &str, glob: &str) -> Result<usize, Report> {
+    let mut db = WritableDatabase::new(db_path, BRASS, DB_CREATE_OR_OPEN)?;
+    let paths = glob::glob(&shellexpand::tilde(glob))?;
+    let stats = indexer::index_files(
+        Some(&mut db),
+        paths,
+        DEFAULT_STEMMER_LANGUAGE,
+        &indexer::IndexOptions::default(),
+    )?;
+    Ok(stats.indexed)
+}
+
+/// Run `query_str`, in the same syntax the interactive query accepts, against the database at
+/// `db_path`. Returns the most relevant documents.
+pub fn search(db_path: &str, query_str: &str) -> Result<Vec<TikaDocument>, Report> {
+    let mut db = open_db(db_path)?;
+    let query = parse_user_query(query_str)?;
+    Ok(
+        query_db(&mut db, query, SortOrder::Relevance, DEFAULT_RESULT_LIMIT)?
+            .matches
+            .into_iter()
+            .map(|m| m.doc)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod lib_tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn index_and_search() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fs::write(
+            dir.path().join("note.md"),
+            "---\ndate: 2021-06-22T12:48:16-0400\ntags: tika\ntitle: Library note\n---\nEmbedded search\n",
+        )
+        .expect("Failed to write note");
+        let db_path = dir.path().join("db");
+        let db_path = db_path.to_str().unwrap();
+        let glob = format!("{}/*.md", dir.path().display());
+
+        assert_eq!(1, index_path(db_path, &glob).expect("Failed to index"));
+        let found = search(db_path, "embedded").expect("Failed to search");
+        assert_eq!(
+            vec!["Library note"],
+            found.iter().map(|d| d.title.as_str()).collect::<Vec<_>>()
+        );
+
+        let mut db = open_db(db_path).expect("Failed to open database");
+        let mut suggest = |q| {
+            xapian_utils::spelling_suggestion(&mut db, q, DEFAULT_STEMMER_LANGUAGE)
+                .expect("Failed to correct spelling")
+        };
+        assert_eq!(
+            Some(String::from("embedded search")),
+            suggest("embeded search")
+        );
+        assert_eq!(None, suggest("embedded search"));
+    }
+}
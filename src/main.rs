@@ -1,21 +1,66 @@
-mod tika_document;
-mod tui_app;
-mod util;
-mod xapian_utils;
-
-use crate::tika_document::{parse_file, TikaDocument};
-use crate::util::glob_files;
-use clap::{App, Arg, ArgMatches, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use color_eyre::Report;
-use xapian_rusty::{Document, Stem, TermGenerator, WritableDatabase, BRASS, DB_CREATE_OR_OPEN};
+use eyre::eyre;
+use log::{debug, warn, LevelFilter};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use tika::indexer::{add_synonyms, delete_from_index, index_files, IndexOptions, INTERRUPTED};
+use tika::tika_document::parse_file;
+use tika::tui_app;
+use tika::util::{
+    default_config_file, default_db_path, default_history_path, glob_files, init_config,
+    open_in_editor, parse_since, Config, DEFAULT_RESULT_FORMAT, DEFAULT_RESULT_LIMIT,
+    DEFAULT_STEMMER_LANGUAGE,
+};
+use tika::xapian_utils::{
+    backlinks_query, count_matches, facets, indexed_language, open_db, parse_query_expr,
+    parse_user_query_with_flags, query_db, query_flags, spelling_suggestion, title_query, Facet,
+    MatchOp, QueryPage, SortOrder, XapianTag,
+};
+use xapian_rusty::{
+    Database, Query, WritableDatabase, BRASS, DB_CREATE_OR_OPEN, DB_CREATE_OR_OVERWRITE, DB_OPEN,
+};
 
-fn setup<'a>(default_config_file: &str) -> Result<ArgMatches, Report> {
+fn setup(default_config_file: &str) -> Result<ArgMatches, Report> {
     if std::env::var("RUST_LIB_BACKTRACE").is_err() {
         std::env::set_var("RUST_LIB_BACKTRACE", "1")
     }
     color_eyre::install()?;
 
-    let cli = App::new("tika")
+    let cli = parse_args(default_config_file, std::env::args_os());
+
+    // RUST_LOG still wins, e.g. RUST_LOG=tika::indexer=trace
+    env_logger::Builder::new()
+        .filter_level(log_level(cli.occurrences_of("v")))
+        .parse_default_env()
+        .format_timestamp(None)
+        .init();
+
+    tui_app::setup_panic();
+
+    Ok(cli)
+}
+
+/// Warnings by default, each `-v` shows one more level of detail
+fn log_level(verbosity: u64) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Parse the command line `args`. The config file comes from `-c`, then the `TIKA_CONFIG`
+/// environment variable, then `default_config_file`.
+fn parse_args<I, T>(default_config_file: &str, args: I) -> ArgMatches
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    App::new("tika")
         .version("1.0")
         .author("Steve <steve@little-fluffy.cloud>")
         .about("Things I Know About: Zettlekasten-like Markdown+FrontMatter Indexer and query tool")
@@ -25,25 +70,113 @@ fn setup<'a>(default_config_file: &str) -> Result<ArgMatches, Report> {
                 .value_name("FILE")
                 .help(
                     format!(
-                        "Point to a config TOML file, defaults to `{}`",
+                        "Point to a config TOML file, defaults to `$TIKA_CONFIG` or else `{}`",
                         default_config_file
                     )
                     .as_str(),
                 )
-                .default_value(&default_config_file)
+                .env("TIKA_CONFIG")
+                .default_value(default_config_file)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("db")
+                .long("db")
+                .value_name("DIRECTORY")
+                .help(
+                    format!(
+                        "Path to the Xapian database, overrides `db-path` in the config, defaults to `{}`",
+                        default_db_path()
+                    )
+                    .as_str(),
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lang")
+                .long("lang")
+                .value_name("LANGUAGE")
+                .help(
+                    format!(
+                        "Stemmer language for indexing and querying, overrides `stemmer-language` in the config, defaults to `{}`",
+                        DEFAULT_STEMMER_LANGUAGE
+                    )
+                    .as_str(),
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sort")
+                .long("sort")
+                .value_name("ORDER")
+                .help("Order matches by relevance or by most recently modified")
+                .possible_values(&["relevance", "mtime"])
+                .default_value("relevance")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("limit")
+                .long("limit")
+                .value_name("COUNT")
+                .help(
+                    format!(
+                        "Maximum number of matches, 0 for no limit, overrides `result-limit` in the config. Defaults to `{}`, or the terminal height when interactive",
+                        DEFAULT_RESULT_LIMIT
+                    )
+                    .as_str(),
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("title-only")
+                .long("title-only")
+                .help("Match the query against note titles only, Ctrl-t switches this in the TUI"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
                 .multiple(true)
-                .help("Sets the level of verbosity"),
+                .help("Log more detail to stderr, repeat for debug and trace output"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Don't show a progress counter while indexing"),
         )
         .arg(
             Arg::with_name("update-index")
                 .short("i")
                 .help("Index data rather than querying the DB"),
         )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("With -i or reindex, parse the files and report on them without touching the DB"),
+        )
+        .arg(
+            Arg::with_name("include-drafts")
+                .long("include-drafts")
+                .help("Index notes marked `draft: true` in their front matter too"),
+        )
+        .arg(
+            Arg::with_name("editor-cmd")
+                .long("editor-cmd")
+                .value_name("COMMAND")
+                .help("How Ctrl-o opens the selected files and `query --open-first` the top match, e.g. `code --wait {path}`, overrides `editor-cmd` in the config. Defaults to `$VISUAL`, `$EDITOR` or `vi`")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("print0")
+                .short("0")
+                .long("print0")
+                .help("Separate the selected paths with NUL rather than newline, for `xargs -0`"),
+        )
+        .arg(
+            Arg::with_name("stats-json")
+                .long("stats-json")
+                .help("Emit indexing statistics as JSON instead of a summary line"),
+        )
         .arg(
             Arg::with_name("source")
                 .short("s")
@@ -51,93 +184,517 @@ fn setup<'a>(default_config_file: &str) -> Result<ArgMatches, Report> {
                 .help("Glob path to markdown files to load")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("since")
+                .long("since")
+                .value_name("WHEN")
+                .help("With -i, only index files modified since an RFC 3339 timestamp or a duration ago, e.g. `12h` or `7d`")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .help("Skip markdown files matching this glob, in addition to `exclude-glob` in the config. May be repeated")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
         .subcommand(
             SubCommand::with_name("query")
                 .about("Query the index")
-                .arg(Arg::with_name("query").required(true).help("Query string")),
+                .arg(Arg::with_name("query").help(
+                    "Query string, read from stdin if `-`, or if left out and stdin isn't a terminal",
+                ))
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print relevance and path of each match one per line, just the paths, or the matches as JSON")
+                        .possible_values(&["plain", "paths", "json"])
+                        .default_value("plain")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .long("paths")
+                        .help("Same as `--format paths`, e.g. for `vim $(tika query tag:todo --paths)`"),
+                )
+                .arg(
+                    Arg::with_name("open-first")
+                        .long("open-first")
+                        .conflicts_with_all(&["format", "paths"])
+                        .help("Open the top match in the editor instead of printing the matches, see --editor-cmd"),
+                ),
         )
-        .get_matches();
-
-    tui_app::setup_panic();
-
-    Ok(cli)
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Show how a query is parsed, without running it")
+                .arg(Arg::with_name("query").help(
+                    "Query string, read from stdin if `-`, or if left out and stdin isn't a terminal",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("count")
+                .about("Print the number of matches for a query, exits non-zero if there are none")
+                .arg(Arg::with_name("query").required(true).help("Query string"))
+                .arg(
+                    Arg::with_name("exact")
+                        .long("exact")
+                        .help("Count every match rather than relying on Xapian's estimate"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backlinks")
+                .about("List the notes with a [[wikilink]] to a note")
+                .arg(
+                    Arg::with_name("note")
+                        .required(true)
+                        .help("Title of the note, or the path of its file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tags")
+                .about("List every tag in the index")
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .help("Show how many documents carry each tag"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("facets")
+                .about("List the distinct words indexed for a field")
+                .arg(
+                    Arg::with_name("field")
+                        .required(true)
+                        .possible_values(&["author", "tag", "title"])
+                        .help("Field to list values of"),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .help("Show how many documents have each value"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print values one per line, or as JSON")
+                        .possible_values(&["plain", "json"])
+                        .default_value("plain")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Manage the config file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("init")
+                        .about("Write a commented default config to the -c path")
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .help("Overwrite the config file if it already exists"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reindex")
+                .about("Rebuild the index from scratch, dropping documents for files that no longer exist"),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Remove a document from the index")
+                .arg(
+                    Arg::with_name("filename")
+                        .required(true)
+                        .help("Filename (or path) of the document to remove"),
+                ),
+        )
+        .get_matches_from(args)
 }
 
 fn main() -> Result<(), Report> {
-    let default_config_file = shellexpand::tilde("~/.config/tika/tika.toml");
+    let default_config_file = default_config_file();
     let cli = setup(&default_config_file)?;
+    let cfg_file = cli.value_of("config").unwrap();
+
+    // Before loading the config, so a broken one can be replaced with --force
+    if let Some(init) = cli
+        .subcommand_matches("config")
+        .and_then(|config| config.subcommand_matches("init"))
+    {
+        init_config(cfg_file, init.is_present("force"))?;
+        println!(
+            "Wrote {}, edit source-glob to point at your notes",
+            cfg_file
+        );
+        return Ok(());
+    }
+
+    let cfg = Config::load(cfg_file)?;
+    let db_path = cfg.db_path(cli.value_of("db"));
+    let lang = cfg.stemmer_language(cli.value_of("lang"));
+
+    if let Some(delete) = cli.subcommand_matches("delete") {
+        let filename = delete.value_of("filename").unwrap();
+        let mut db = WritableDatabase::new(&db_path, BRASS, DB_OPEN)?;
+        if delete_from_index(&mut db, filename)? {
+            db.commit()?;
+            println!("🗑 Removed {} from the index", filename);
+        } else {
+            eprintln!("❌ No document for {} in the index", filename);
+        }
+        return Ok(());
+    }
+
+    if let Some(tags) = cli.subcommand_matches("tags") {
+        let mut db = open_db(&db_path)?;
+        print_facets(&facets(&mut db, XapianTag::Tag)?, tags.is_present("count"));
+        return Ok(());
+    }
+
+    if let Some(args) = cli.subcommand_matches("facets") {
+        let mut db = open_db(&db_path)?;
+        let field: XapianTag = args.value_of("field").unwrap().parse()?;
+        let facets = facets(&mut db, field)?;
+        if args.value_of("format") == Some("json") {
+            println!("{}", serde_json::to_string(&facets)?);
+        } else {
+            print_facets(&facets, args.is_present("count"));
+        }
+        return Ok(());
+    }
+
+    if cli.is_present("dry-run")
+        && (cli.subcommand_matches("reindex").is_some() || cli.occurrences_of("update-index") > 0)
+    {
+        index(&cli, &cfg, None, &lang)?;
+        eprintln!("Dry run, nothing was written to {}", db_path);
+        return Ok(());
+    }
 
-    // If requested, reindex the data
+    if cli.subcommand_matches("reindex").is_some() {
+        // Start from an empty database so renamed or deleted files don't linger
+        let mut db = WritableDatabase::new(&db_path, BRASS, DB_CREATE_OR_OVERWRITE)?;
+        index(&cli, &cfg, Some(&mut db), &lang)?;
+        println!("{} documents in the index", db.get_doccount()?);
+        return Ok(());
+    }
+
+    // If requested, update the index in place
     if cli.occurrences_of("update-index") > 0 {
-        let mut db = WritableDatabase::new("mydb", BRASS, DB_CREATE_OR_OPEN)?;
-        let mut tg = TermGenerator::new()?;
-        let mut stemmer = Stem::new("en")?;
-        tg.set_stemmer(&mut stemmer)?;
-
-        // TODO is there a rustier way to do this?
-        for entry in glob_files(
-            &cli.value_of("config").unwrap(),
-            cli.value_of("source"),
-            cli.occurrences_of("v") as i8,
-        )
-        .expect("Failed to read glob pattern")
-        {
-            match entry {
-                // TODO convert this to iterator style using map/filter
-                Ok(path) => {
-                    if let Ok(tikadoc) = parse_file(&path) {
-                        update_index(&mut db, &mut tg, &tikadoc)?;
-                        if cli.occurrences_of("v") > 0 {
-                            println!("✅ {}", tikadoc.filename);
-                        }
-                    } else {
-                        eprintln!("❌ Failed to load file {}", path.display());
-                    }
+        let mut db = WritableDatabase::new(&db_path, BRASS, DB_CREATE_OR_OPEN)?;
+        index(&cli, &cfg, Some(&mut db), &lang)?;
+    }
+
+    let sort: SortOrder = cli.value_of("sort").unwrap().parse()?;
+    let flags = query_flags(&cfg.query_flags())?;
+    let default_op = cfg.default_operator()?;
+    let limit = cfg.result_limit(cli.value_of("limit").map(str::parse).transpose()?);
+    let template = cfg.result_format();
+
+    if let Some(args) = cli.subcommand_matches("explain") {
+        let qstr = query_string(args.value_of("query"))?;
+        // The index, if there is one yet, tells which custom fields such as `project:` exist
+        let mut db = open_db(&db_path).ok();
+        let explained = parse_query_expr(&qstr).and_then(|expr| {
+            let mut query =
+                parse_user_query_with_flags(&qstr, &lang, flags, default_op, db.as_mut())?;
+            Ok((expr, query.get_description()))
+        });
+        match explained {
+            Ok((expr, description)) => {
+                println!("Expression: {}", expr);
+                println!("Tokens:");
+                for (column, token) in expr.tokens() {
+                    println!("{:>4}  {}", column, token);
                 }
+                println!("Query: {}", description);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
 
-                Err(e) => eprintln!("❌ {:?}", e),
+    // Non-interactive query, for scripting
+    if let Some(query) = cli.subcommand_matches("query") {
+        let mut db = open_db(&db_path)?;
+        if let Some(indexed) = indexed_language(&mut db)?.filter(|indexed| *indexed != lang) {
+            warn!(
+                "Index was built with stemmer language '{}' but querying with '{}'",
+                indexed, lang
+            );
+        }
+        let qstr = query_string(query.value_of("query"))?;
+        let mut q = parse_cli_query(&cli, &qstr, &lang, flags, default_op, &mut db)?;
+        // At -vv, to see how operators and field prefixes were applied
+        debug!("Running {}", q.get_description());
+        if query.is_present("open-first") {
+            let QueryPage { matches, .. } = query_db(&mut db, q, sort, 1)?;
+            match matches.first() {
+                Some(m) => {
+                    let template = cfg.editor_cmd(cli.value_of("editor-cmd"));
+                    // Doesn't return, the editor replaces this process
+                    match open_in_editor(&[m.doc.full_path.clone()], template.as_deref())? {}
+                }
+                None => {
+                    eprintln!("❌ No matches for {}", qstr);
+                    if let Some(suggestion) = spelling_suggestion(&mut db, &qstr, &lang)? {
+                        eprintln!("Did you mean: {}", suggestion);
+                    }
+                    std::process::exit(1);
+                }
+            }
+        }
+        let QueryPage { matches, estimated } =
+            query_db(&mut db, q, sort, limit.unwrap_or(DEFAULT_RESULT_LIMIT))?;
+        let shown = matches.len();
+        let format = if query.is_present("paths") {
+            "paths"
+        } else {
+            query.value_of("format").unwrap()
+        };
+        match format {
+            "json" => println!("{}", serde_json::to_string(&matches)?),
+            // The same paths the interactive query prints for the selected matches
+            "paths" => {
+                for m in matches {
+                    println!("{}", m.doc.full_path.to_string_lossy());
+                }
+            }
+            // Paths unless the config has a `result-format` to render the matches with
+            _ => {
+                for m in matches {
+                    let row = match &template {
+                        Some(template) => m.doc.format(template),
+                        None => m.doc.full_path.to_string_lossy().into_owned(),
+                    };
+                    println!("{:>3}% {}", m.percent, row);
+                }
             }
         }
+        // On stderr, so it doesn't get mixed up with the matches in a pipeline
+        if estimated > shown {
+            eprintln!("Showing {} of about {} matches", shown, estimated);
+        }
+        if let Some(suggestion) = spelling_suggestion(&mut db, &qstr, &lang)? {
+            eprintln!("Did you mean: {}", suggestion);
+        }
+        return Ok(());
+    }
+
+    if let Some(args) = cli.subcommand_matches("count") {
+        let mut db = open_db(&db_path)?;
+        let mut q = parse_cli_query(
+            &cli,
+            args.value_of("query").unwrap(),
+            &lang,
+            flags,
+            default_op,
+            &mut db,
+        )?;
+        debug!("Running {}", q.get_description());
+        let count = count_matches(&mut db, q, args.is_present("exact"))?;
+        println!("{}", count);
+        if count == 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-        db.commit()?;
+    if let Some(args) = cli.subcommand_matches("backlinks") {
+        let mut db = open_db(&db_path)?;
+        let q = backlinks_query(&link_targets(args.value_of("note").unwrap()))?;
+        for m in query_db(&mut db, q, sort, limit.unwrap_or(DEFAULT_RESULT_LIMIT))?.matches {
+            println!("{}", m.doc.full_path.to_string_lossy());
+        }
+        return Ok(());
     }
 
-    let mut iter = IntoIterator::into_iter(tui_app::interactive_query()?); // strings is moved here
-    while let Some(s) = iter.next() {
-        // next() moves a string out of the iter
-        println!("{}", s);
+    let opts = tui_app::InteractiveOptions {
+        flags,
+        default_op,
+        sort,
+        limit,
+        debounce: cfg.debounce_interval(),
+        tick_rate: cfg.tick_rate(),
+        result_format: template.unwrap_or_else(|| DEFAULT_RESULT_FORMAT.to_string()),
+        history_path: default_history_path(),
+        title_only: cli.is_present("title-only"),
+    };
+    let selection = tui_app::interactive_query(&db_path, &lang, &opts)?;
+    if selection.edit && !selection.paths.is_empty() {
+        let template = cfg.editor_cmd(cli.value_of("editor-cmd"));
+        // Doesn't return, the editor replaces this process
+        match open_in_editor(&selection.paths, template.as_deref())? {}
+    }
+
+    // NUL separated paths are safe to hand to `xargs -0` whatever characters they contain. The
+    // paths are written as they are, they needn't be UTF-8.
+    let separator = if cli.is_present("print0") {
+        b'\0'
+    } else {
+        b'\n'
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for path in selection.paths {
+        out.write_all(path.as_bytes())?;
+        out.write_all(&[separator])?;
     }
 
     Ok(())
 }
 
-fn update_index(
-    db: &mut WritableDatabase,
-    tg: &mut TermGenerator,
-    tikadoc: &TikaDocument,
-) -> Result<(), Report> {
-    // Create a new Xapian Document to store attributes on the passed-in TikaDocument
-    let mut doc = Document::new()?;
-    tg.set_document(&mut doc)?;
+/// The query given as an argument, or read from stdin when the argument is `-`. Without an
+/// argument the query is read from stdin unless that's a terminal.
+fn query_string(arg: Option<&str>) -> Result<String, Report> {
+    match arg {
+        Some("-") => {}
+        Some(query) => return Ok(query.to_string()),
+        None if termion::is_tty(&io::stdin()) => {
+            return Err(eyre!("No query given, pass one as an argument or on stdin"))
+        }
+        None => {}
+    }
+
+    let mut query = String::new();
+    io::stdin().read_to_string(&mut query)?;
+    Ok(query)
+}
+
+/// What a wikilink to `note` might name. Given the path of a note's file, that's its title and its
+/// filename without the extension, otherwise `note` is taken to be the title.
+fn link_targets(note: &str) -> Vec<String> {
+    let path = Path::new(note);
+    if !path.is_file() {
+        return vec![note.to_string()];
+    }
+
+    let mut targets = Vec::new();
+    match parse_file(&path.to_path_buf()) {
+        Ok(doc) if !doc.title.is_empty() => targets.push(doc.title),
+        Ok(_) => {}
+        Err(e) => warn!("{}", e),
+    }
+    if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+        targets.push(stem.to_string());
+    }
+    targets
+}
 
-    tg.index_text_with_prefix(&tikadoc.author, "A")?;
-    tg.index_text_with_prefix(&tikadoc.date_str()?, "D")?;
-    tg.index_text_with_prefix(&tikadoc.filename, "F")?;
-    tg.index_text_with_prefix(&tikadoc.full_path.clone().into_string().unwrap(), "F")?;
-    tg.index_text_with_prefix(&tikadoc.title, "S")?;
-    tg.index_text_with_prefix(&tikadoc.subtitle, "XS")?;
-    for tag in &tikadoc.tags {
-        tg.index_text_with_prefix(&tag, "K")?;
+/// Parse `qstr` as a full query, or with `--title-only` as words of the note titles
+fn parse_cli_query(
+    cli: &ArgMatches,
+    qstr: &str,
+    lang: &str,
+    flags: i16,
+    default_op: MatchOp,
+    db: &mut Database,
+) -> Result<Query, Report> {
+    if cli.is_present("title-only") {
+        title_query(qstr, lang, flags, Some(db))
+    } else {
+        parse_user_query_with_flags(qstr, lang, flags, default_op, Some(db))
+    }
+}
+
+/// Print one facet value per line, tab separated from its document count if `count`
+fn print_facets(facets: &[Facet], count: bool) {
+    for facet in facets {
+        if count {
+            println!("{}\t{}", facet.value, facet.count);
+        } else {
+            println!("{}", facet.value);
+        }
     }
+}
 
-    tg.index_text(&tikadoc.body)?;
+/// Index every file matched by the source globs into `db`, or only parse them without one, then
+/// print a summary
+fn index(
+    cli: &ArgMatches,
+    cfg: &Config,
+    mut db: Option<&mut WritableDatabase>,
+    lang: &str,
+) -> Result<(), Report> {
+    // Stop at the next file on Ctrl-C rather than dying, so what's been indexed so far is committed
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))?;
 
-    // Convert the TikaDocument into JSON and set it in the DB for retrieval later
-    doc.set_data(&serde_json::to_string(&tikadoc).unwrap())?;
+    if let Some(db) = db.as_deref_mut() {
+        add_synonyms(db, &cfg.synonyms())?;
+    }
 
-    let id = "Q".to_owned() + &tikadoc.filename;
-    doc.add_boolean_term(&id)?;
-    db.replace_document(&id, &mut doc)?;
+    let paths = glob_files(
+        cfg,
+        cli.value_of("source"),
+        &cli.values_of("exclude")
+            .map(|v| v.collect::<Vec<_>>())
+            .unwrap_or_default(),
+        cli.value_of("since").map(parse_since).transpose()?,
+    )?;
+    let opts = IndexOptions {
+        heading_weight: cfg.heading_weight(),
+        include_drafts: cli.is_present("include-drafts"),
+        // The per-file log lines of verbose mode would break up the progress counter
+        progress: !cli.is_present("quiet")
+            && cli.occurrences_of("v") == 0
+            && termion::is_tty(&io::stdout()),
+        commit_batch_size: cfg.commit_batch_size(),
+    };
+    let stats = index_files(db, paths, lang, &opts)?;
+
+    if cli.is_present("stats-json") {
+        println!("{}", serde_json::to_string(&stats)?);
+    } else {
+        println!("{}", stats);
+    }
+    if stats.interrupted {
+        // The conventional exit status for SIGINT
+        std::process::exit(130);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+
+    #[test]
+    fn config_precedence() {
+        let config = |args: &[&str]| {
+            parse_args("/default/tika.toml", args)
+                .value_of("config")
+                .map(String::from)
+        };
+
+        std::env::set_var("TIKA_CONFIG", "/env/tika.toml");
+        assert_eq!(Some("/env/tika.toml".to_string()), config(&["tika"]));
+        // The flag wins over the environment
+        assert_eq!(
+            Some("/flag/tika.toml".to_string()),
+            config(&["tika", "-c", "/flag/tika.toml"])
+        );
+
+        std::env::remove_var("TIKA_CONFIG");
+        assert_eq!(Some("/default/tika.toml".to_string()), config(&["tika"]));
+    }
+
+    #[test]
+    fn open_first() {
+        let cli = parse_args(
+            "/default/tika.toml",
+            &["tika", "query", "meeting notes", "--open-first"],
+        );
+        let query = cli.subcommand_matches("query").unwrap();
+        assert!(query.is_present("open-first"));
+        assert_eq!(Some("meeting notes"), query.value_of("query"));
+    }
+}
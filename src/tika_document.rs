@@ -1,11 +1,32 @@
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime};
 use color_eyre::Report;
 use eyre::{eyre, Result};
-use serde::{de, Deserialize, Deserializer, Serialize};
-use std::io::{Error, ErrorKind};
-use std::{ffi::OsString, fmt, fs, io, marker::PhantomData};
+use pulldown_cmark::{Event, Parser, Tag};
+use serde::{de, ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::{ffi::OsString, fmt, fs, io, marker::PhantomData, time::UNIX_EPOCH};
 use yaml_rust::YamlEmitter;
 
+/// Front matter date formats with a timezone, tried after RFC 3339 and RFC 2822
+const DATETIME_FORMATS: &[&str] = &["%Y-%m-%dT%T%z", "%Y-%m-%d %T%z", "%Y-%m-%d %T %z"];
+/// Formats without a timezone, these are taken to be UTC
+const NAIVE_DATETIME_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%T",
+    "%Y-%m-%d %T",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+/// Formats without a time of day, these are taken to be midnight UTC
+const DATE_FORMATS: &[&str] = &[
+    "%Y-%m-%d",
+    "%Y/%m/%d",
+    "%B %d, %Y",
+    "%b %d, %Y",
+    "%d %B %Y",
+    "%d %b %Y",
+];
+
 /// Representation for a given Markdown + FrontMatter file; Example:
 /// ---
 /// author: Steve Sosik
@@ -17,18 +38,30 @@ use yaml_rust::YamlEmitter;
 ///
 /// Some note here formatted with Markdown syntax
 ///
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct TikaDocument {
     /// Inherent metadata about the document
     #[serde(default)]
     pub filename: String,
-    #[serde(default)]
+    /// Stored as a UTF-8 string, lossily converted if need be, so the JSON document data can give
+    /// back the path to open
+    #[serde(
+        default,
+        serialize_with = "os_string_lossy",
+        deserialize_with = "string_into_os_string"
+    )]
     pub full_path: OsString,
+    /// Filesystem modification time in seconds since the Unix epoch
+    #[serde(default)]
+    pub mtime: i64,
 
     /// FrontMatter-derived metadata about the document
     #[serde(default)]
     pub author: String,
-    /// RFC 3339 based timestamp
+    /// Timestamp in any of the formats `parse_date` accepts, documents with a date that can't be
+    /// parsed are indexed without one. A bare TOML date such as `date = 2021-06-22` is kept as
+    /// written.
+    #[serde(default, deserialize_with = "scalar_as_string")]
     pub date: String,
 
     #[serde(deserialize_with = "string_or_list_string")]
@@ -39,30 +72,114 @@ pub struct TikaDocument {
     #[serde(default)]
     pub subtitle: String,
 
+    /// Notes still being drafted are left out of the index, see `index_files`
+    #[serde(default)]
+    pub draft: bool,
+
+    /// Targets of the `[[Note Title]]` wikilinks in the body
+    #[serde(default)]
+    pub links: Vec<String>,
+
     /// The Markdown-formatted body of the document
     #[serde(default)]
     pub body: String,
+
+    /// Any other front matter fields, e.g. `project:` or `status:`, indexed under a prefix derived
+    /// from the key, see `xapian_utils::custom_field_prefix`. They're serialized under their own
+    /// `fields` key, so a `rank:` field can't clash with `QueryMatch`'s rank.
+    #[serde(
+        flatten,
+        serialize_with = "nested_fields",
+        deserialize_with = "scalars_as_strings"
+    )]
+    pub extra: HashMap<String, String>,
 }
 
 impl TikaDocument {
-    pub(crate) fn date_str(&self) -> Result<String, Report> {
-        if let Ok(t) = self.parse_date() {
-            let ret = t.with_timezone(&chrono::Utc).to_rfc3339();
-            return Ok(ret);
+    /// The date normalized to UTC as a lexicographically sortable `YYYYMMDD` string. It's indexed
+    /// under the `D` prefix, so `date:20210622` matches the day, and stored in `DATE_SLOT` for
+    /// range queries and partial dates such as `date:202106`.
+    pub fn date_str(&self) -> Result<String, Report> {
+        let t = self.parse_date()?;
+        Ok(t.with_timezone(&chrono::Utc).format("%Y%m%d").to_string())
+    }
+    /// The visible text of the Markdown body, for indexing. Syntax such as `#` and `*` and link
+    /// URLs are dropped, while headings, paragraphs, list items and code are kept.
+    pub fn plain_body(&self) -> String {
+        let mut text = String::with_capacity(self.body.len());
+        for event in Parser::new(&self.body) {
+            match event {
+                Event::Text(t) | Event::Code(t) => text.push_str(&t),
+                Event::SoftBreak | Event::HardBreak => text.push('\n'),
+                // Keep words from adjoining elements apart
+                Event::End(_) => text.push(' '),
+                _ => {}
+            }
+        }
+        text
+    }
+    /// The text of every Markdown heading in the body, one heading per line
+    pub fn headings(&self) -> String {
+        let mut text = String::new();
+        let mut in_heading = false;
+        for event in Parser::new(&self.body) {
+            match event {
+                Event::Start(Tag::Heading(_)) => in_heading = true,
+                Event::End(Tag::Heading(_)) => {
+                    in_heading = false;
+                    text.push('\n');
+                }
+                Event::Text(t) | Event::Code(t) if in_heading => text.push_str(&t),
+                _ => {}
+            }
         }
-        Err(eyre!("❌ Failed to convert path to date '{}'", &self.date))
+        text
     }
-    pub(crate) fn parse_date(&self) -> Result<DateTime<FixedOffset>, Report> {
-        if let Ok(rfc3339) = DateTime::parse_from_rfc3339(&self.date) {
-            return Ok(rfc3339);
-        } else if let Ok(s) = DateTime::parse_from_str(&self.date, &String::from("%Y-%m-%dT%T%z")) {
-            return Ok(s);
+    /// Parse the front matter date. Besides RFC 3339 and RFC 2822 this accepts the formats in
+    /// `DATETIME_FORMATS`, `NAIVE_DATETIME_FORMATS` and `DATE_FORMATS`, e.g. `2021-06-22` or
+    /// `June 22, 2021`.
+    pub fn parse_date(&self) -> Result<DateTime<FixedOffset>, Report> {
+        let date = self.date.trim();
+        let utc = FixedOffset::east(0);
+
+        if let Ok(t) = DateTime::parse_from_rfc3339(date) {
+            return Ok(t);
+        }
+        if let Ok(t) = DateTime::parse_from_rfc2822(date) {
+            return Ok(t);
+        }
+        for fmt in DATETIME_FORMATS {
+            if let Ok(t) = DateTime::parse_from_str(date, fmt) {
+                return Ok(t);
+            }
         }
-        eprintln!("❌ Failed to convert path to str '{}'", &self.filename);
-        Err(eyre!(
-            "❌ Failed to convert path to str '{}'",
-            &self.filename
-        ))
+        for fmt in NAIVE_DATETIME_FORMATS {
+            if let Ok(t) = NaiveDateTime::parse_from_str(date, fmt) {
+                return Ok(DateTime::from_utc(t, utc));
+            }
+        }
+        for fmt in DATE_FORMATS {
+            if let Ok(d) = NaiveDate::parse_from_str(date, fmt) {
+                return Ok(DateTime::from_utc(d.and_hms(0, 0, 0), utc));
+            }
+        }
+
+        Err(eyre!("Invalid date '{}' in {}", self.date, self.filename))
+    }
+    /// Render the document for a list of matches by substituting `{title}`, `{filename}`,
+    /// `{date}`, `{author}` and `{tags}` in `template`, e.g. `{date} {title}`. The date is shown as
+    /// `YYYY-MM-DD` when it can be parsed, tags are comma-separated.
+    pub fn format(&self, template: &str) -> String {
+        let date = match self.parse_date() {
+            Ok(t) => t.format("%Y-%m-%d").to_string(),
+            Err(_) => self.date.clone(),
+        };
+        template
+            .replace("{title}", &self.title)
+            .replace("{filename}", &self.filename)
+            .replace("{date}", &date)
+            .replace("{author}", &self.author)
+            .replace("{tags}", &self.tags.join(", "))
     }
 }
 
@@ -99,34 +216,717 @@ where
     deserializer.deserialize_any(StringOrVec(PhantomData))
 }
 
-pub(crate) fn parse_file(path: &std::path::PathBuf) -> Result<TikaDocument, io::Error> {
-    let full_path = path.to_str().unwrap();
-    let s = fs::read_to_string(full_path)?;
+/// The field the toml crate hands its bare dates and times over in, as a single entry map
+const TOML_DATETIME_FIELD: &str = "$__toml_private_datetime";
+
+/// A front matter scalar as a string, None for lists, tables and nulls. Numbers and booleans are
+/// converted, TOML's bare dates and times are kept as written, e.g. `2021-06-22`.
+fn scalar_string(value: serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        serde_json::Value::Object(mut map) if map.len() == 1 => {
+            match map.remove(TOML_DATETIME_FIELD) {
+                Some(serde_json::Value::String(s)) => Some(s),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Deserialize any front matter scalar into a string, see `scalar_string`. A null is empty.
+fn scalar_as_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(String::new()),
+        value => scalar_string(value)
+            .ok_or_else(|| de::Error::custom("expected a string, number, boolean or date")),
+    }
+}
+
+/// The key `nested_fields` serializes the custom front matter fields under
+const FIELDS_KEY: &str = "fields";
+
+/// Collect the front matter fields `TikaDocument` has no field for. Scalars are kept as strings,
+/// see `scalar_string`, lists and tables are left out. The fields `nested_fields` wrote to the
+/// JSON document data are collected from under their `fields` key.
+fn scalars_as_strings<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let fields: HashMap<String, serde_json::Value> = HashMap::deserialize(deserializer)?;
+    let mut scalars = HashMap::new();
+    for (key, value) in fields {
+        match value {
+            serde_json::Value::Object(nested) if key == FIELDS_KEY => scalars.extend(
+                nested
+                    .into_iter()
+                    .filter_map(|(key, value)| scalar_string(value).map(|s| (key, s))),
+            ),
+            value => scalars.extend(scalar_string(value).map(|s| (key, s))),
+        }
+    }
+    Ok(scalars)
+}
+
+/// Serialize the custom front matter fields as one `fields` object. `extra` is flattened so
+/// deserializing collects the unknown front matter keys, this keeps them apart from the rest.
+fn nested_fields<S>(fields: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry(FIELDS_KEY, fields)?;
+    map.end()
+}
+
+/// Serialize an OsString as a plain string rather than serde's platform-specific representation
+fn os_string_lossy<S>(value: &OsString, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&value.to_string_lossy())
+}
+
+/// Deserialize a string written by `os_string_lossy`
+fn string_into_os_string<'de, D>(deserializer: D) -> Result<OsString, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(OsString::from(String::deserialize(deserializer)?))
+}
+
+/// Split `+++` delimited TOML front matter from the content following it, None if the document
+/// doesn't start with a `+++` line
+fn split_toml_front_matter(s: &str) -> Option<(&str, &str)> {
+    let first_line_end = s.find('\n')?;
+    if s[..first_line_end].trim() != "+++" {
+        return None;
+    }
 
-    let (yaml, content) = frontmatter::parse_and_find_content(&s).unwrap();
-    match yaml {
-        Some(yaml) => {
-            let mut out_str = String::new();
-            {
-                let mut emitter = YamlEmitter::new(&mut out_str);
-                emitter.dump(&yaml).unwrap(); // dump the YAML object to a String
+    let front_start = first_line_end + 1;
+    let mut offset = front_start;
+    for line in s[front_start..].split('\n') {
+        if line.trim() == "+++" {
+            let content_start = (offset + line.len() + 1).min(s.len());
+            return Some((&s[front_start..offset], &s[content_start..]));
+        }
+        offset += line.len() + 1;
+    }
+    None
+}
+
+/// Why `parse_file` couldn't make a `TikaDocument` of a file, each variant names the file
+#[derive(Debug)]
+pub enum ParseError {
+    /// The file couldn't be read or its metadata looked up, e.g. it doesn't exist
+    Io { path: PathBuf, source: io::Error },
+    /// The file isn't valid UTF-8
+    NotUtf8 { path: PathBuf },
+    /// The file doesn't start with `---` YAML or `+++` TOML front matter
+    NoFrontMatter { path: PathBuf },
+    /// The YAML front matter is malformed or doesn't fit `TikaDocument`
+    Yaml { path: PathBuf, message: String },
+    /// The TOML front matter is malformed or doesn't fit `TikaDocument`
+    Toml {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io { path, source } => {
+                write!(f, "Failed to read {}: {}", path.display(), source)
             }
+            ParseError::NotUtf8 { path } => write!(f, "{} is not valid UTF-8", path.display()),
+            ParseError::NoFrontMatter { path } => write!(
+                f,
+                "No YAML or TOML front matter found in {}",
+                path.display()
+            ),
+            ParseError::Yaml { path, message } => write!(
+                f,
+                "Failed to parse YAML front matter in {}: {}",
+                path.display(),
+                message
+            ),
+            ParseError::Toml { path, source } => write!(
+                f,
+                "Failed to parse TOML front matter in {}: {}",
+                path.display(),
+                source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io { source, .. } => Some(source),
+            ParseError::Toml { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a Markdown file with either YAML (`---` delimited) or TOML (`+++` delimited) front matter
+pub fn parse_file(path: &PathBuf) -> Result<TikaDocument, ParseError> {
+    let io_err = |source: io::Error| ParseError::Io {
+        path: path.clone(),
+        source,
+    };
+    let yaml_err = |e: &dyn fmt::Display| ParseError::Yaml {
+        path: path.clone(),
+        message: e.to_string(),
+    };
+    let s = fs::read_to_string(path).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => ParseError::NotUtf8 { path: path.clone() },
+        _ => io_err(e),
+    })?;
 
-            let mut doc: TikaDocument = serde_yaml::from_str(&out_str).unwrap();
-            // TODO Is this check necessary?
-            if doc.filename == *"" {
-                doc.filename = String::from(path.file_name().unwrap().to_str().unwrap());
+    let (mut doc, content) = if let Some((front, content)) = split_toml_front_matter(&s) {
+        let doc: TikaDocument = toml::from_str(front).map_err(|source| ParseError::Toml {
+            path: path.clone(),
+            source,
+        })?;
+        (doc, content)
+    } else {
+        let (yaml, content) = frontmatter::parse_and_find_content(&s).map_err(|e| yaml_err(&e))?;
+        match yaml {
+            Some(yaml) => {
+                let mut out_str = String::new();
+                {
+                    let mut emitter = YamlEmitter::new(&mut out_str);
+                    // dump the YAML object to a String
+                    emitter.dump(&yaml).map_err(|e| yaml_err(&e))?;
+                }
+
+                let doc: TikaDocument = serde_yaml::from_str(&out_str).map_err(|e| yaml_err(&e))?;
+                (doc, content)
             }
+            None => return Err(ParseError::NoFrontMatter { path: path.clone() }),
+        }
+    };
+
+    // TODO Is this check necessary?
+    if doc.filename == *"" {
+        // Lossily, a filename that isn't UTF-8 shouldn't stop the note being indexed
+        doc.filename = path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
+
+    doc.full_path = path.clone().into_os_string();
 
-            doc.full_path = OsString::from(full_path);
+    doc.mtime = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(io_err)?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
 
-            doc.body = content.to_string();
+    doc.body = strip_leading_delimiters(content).to_string();
+    doc.links = wikilinks(&doc.body);
 
-            Ok(doc)
+    Ok(doc)
+}
+
+/// Targets of the `[[Note Title]]` wikilinks in `body`, in order of first appearance. Anchors and
+/// aliases, as in `[[Note Title#Section|alias]]`, are dropped.
+fn wikilinks(body: &str) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find("[[") {
+        rest = &rest[start + 2..];
+        let end = match rest.find("]]") {
+            Some(end) => end,
+            None => break,
+        };
+        // For unbalanced brackets like `[[a [[b]]` only the innermost link counts
+        let inner = &rest[..end];
+        let inner = inner.rfind("[[").map_or(inner, |i| &inner[i + 2..]);
+        let target = inner
+            .split(|c| c == '#' || c == '|')
+            .next()
+            .unwrap_or("")
+            .trim();
+        if !target.is_empty() && !target.contains('\n') && !links.iter().any(|l| l == target) {
+            links.push(target.to_string());
+        }
+        rest = &rest[end + 2..];
+    }
+    links
+}
+
+/// Drop any leftover front matter delimiter lines, and the blank lines around them, from the
+/// start of the body so they aren't stored or indexed as content
+fn strip_leading_delimiters(content: &str) -> &str {
+    let mut rest = content;
+    loop {
+        let line_end = rest.find('\n').map_or(rest.len(), |i| i + 1);
+        match rest[..line_end].trim() {
+            "" | "---" | "+++" | "..." if !rest.is_empty() => rest = &rest[line_end..],
+            _ => return rest,
         }
-        None => Err(Error::new(
-            ErrorKind::Other,
-            format!("Failed to process file {}", path.display()),
-        )),
+    }
+}
+
+#[cfg(test)]
+mod plain_body_tests {
+    use super::*;
+
+    fn plain(body: &str) -> String {
+        TikaDocument {
+            body: String::from(body),
+            ..TikaDocument::default()
+        }
+        .plain_body()
+    }
+
+    #[test]
+    fn markdown_syntax_dropped() {
+        let text = plain(
+            "# Foo\n\nSome *bold* text, a [link](https://example.com) and `code`\n\n- item\n",
+        );
+        assert!(!text.contains('#'), "{:?}", text);
+        assert!(!text.contains('*'), "{:?}", text);
+        assert!(!text.contains("example.com"), "{:?}", text);
+        for word in &["Foo", "bold", "link", "code", "item"] {
+            assert!(text.contains(word), "{} missing from {:?}", word, text);
+        }
+    }
+
+    #[test]
+    fn headings() {
+        let doc = TikaDocument {
+            body: String::from("# Foo\n\nProse\n\n## Bar `baz`\n\nMore prose\n"),
+            ..TikaDocument::default()
+        };
+        assert_eq!("Foo\nBar baz\n", doc.headings());
+    }
+
+    #[test]
+    fn code_block_contents_kept() {
+        let text = plain("```rust\nfn main() {}\n```\n");
+        assert!(text.contains("fn main() {}"), "{:?}", text);
+        assert!(!text.contains("```"), "{:?}", text);
+    }
+}
+
+#[cfg(test)]
+mod parse_date_tests {
+    use super::*;
+
+    fn doc(date: &str) -> TikaDocument {
+        TikaDocument {
+            date: String::from(date),
+            ..TikaDocument::default()
+        }
+    }
+
+    fn utc(date: &str) -> String {
+        let t = doc(date).parse_date().expect("Failed to parse date");
+        t.with_timezone(&chrono::Utc).to_rfc3339()
+    }
+
+    #[test]
+    fn with_timezone() {
+        assert_eq!(
+            "2021-06-22T16:48:16+00:00",
+            utc("2021-06-22T12:48:16-04:00")
+        );
+        assert_eq!("2021-06-22T16:48:16+00:00", utc("2021-06-22T12:48:16-0400"));
+        assert_eq!("2021-06-22T16:48:16+00:00", utc("2021-06-22 12:48:16-0400"));
+        assert_eq!(
+            "2021-06-22T16:48:16+00:00",
+            utc("2021-06-22 12:48:16 -0400")
+        );
+        assert_eq!(
+            "2021-06-22T16:48:16+00:00",
+            utc("Tue, 22 Jun 2021 12:48:16 -0400")
+        );
+    }
+
+    #[test]
+    fn without_timezone() {
+        assert_eq!("2021-06-22T12:48:16+00:00", utc("2021-06-22T12:48:16"));
+        assert_eq!("2021-06-22T12:48:16+00:00", utc("2021-06-22 12:48:16"));
+        assert_eq!("2021-06-22T12:48:00+00:00", utc("2021-06-22T12:48"));
+        assert_eq!("2021-06-22T12:48:00+00:00", utc("2021-06-22 12:48"));
+    }
+
+    #[test]
+    fn date_only() {
+        assert_eq!("2021-06-22T00:00:00+00:00", utc("2021-06-22"));
+        assert_eq!("2021-06-22T00:00:00+00:00", utc(" 2021-06-22 "));
+        assert_eq!("2021-06-22T00:00:00+00:00", utc("2021/06/22"));
+        assert_eq!("2021-06-22T00:00:00+00:00", utc("June 22, 2021"));
+        assert_eq!("2021-06-22T00:00:00+00:00", utc("Jun 22, 2021"));
+        assert_eq!("2021-06-22T00:00:00+00:00", utc("22 June 2021"));
+        assert_eq!("2021-06-22T00:00:00+00:00", utc("22 Jun 2021"));
+    }
+
+    #[test]
+    fn invalid() {
+        for date in &["", "last tuesday", "2021-13-01", "22/06/2021"] {
+            assert!(doc(date).parse_date().is_err(), "{:?}", date);
+            assert!(doc(date).date_str().is_err(), "{:?}", date);
+        }
+    }
+
+    #[test]
+    fn sortable_date_str() {
+        let date_str = |date| doc(date).date_str().expect("Failed to normalize date");
+        assert_eq!("20210622", date_str("2021-06-22T12:48:16-0400"));
+        assert_eq!("20210622", date_str("June 22, 2021"));
+        assert_eq!("20210101", date_str("2021-01-01"));
+        // Normalized to UTC, which can change the day
+        assert_eq!("20220101", date_str("2021-12-31T22:00:00-0500"));
+        assert!(date_str("2020-12-31") < date_str("2021-01-01"));
+    }
+}
+
+#[cfg(test)]
+mod format_tests {
+    use super::*;
+
+    #[test]
+    fn placeholders() {
+        let doc = TikaDocument {
+            filename: String::from("lighthouses.md"),
+            author: String::from("Steve Sosik"),
+            date: String::from("2021-06-22T12:48:16-04:00"),
+            tags: vec![String::from("tika"), String::from("sea")],
+            title: String::from("Lighthouses"),
+            ..TikaDocument::default()
+        };
+        assert_eq!("Lighthouses", doc.format("{title}"));
+        assert_eq!(
+            "2021-06-22 Lighthouses (lighthouses.md) by Steve Sosik [tika, sea]",
+            doc.format("{date} {title} ({filename}) by {author} [{tags}]")
+        );
+        // Unknown placeholders are left alone
+        assert_eq!("{subject}", doc.format("{subject}"));
+    }
+
+    #[test]
+    fn unparseable_date() {
+        let doc = TikaDocument {
+            date: String::from("someday"),
+            ..TikaDocument::default()
+        };
+        assert_eq!("someday", doc.format("{date}"));
+    }
+}
+
+#[cfg(test)]
+mod wikilinks_tests {
+    use super::*;
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            vec!["Some Note", "Other", "Aliased Note"],
+            wikilinks(
+                "See [[Some Note]] and [[Other#Section]], [[Aliased Note|this one]] or [[Some Note]] again"
+            )
+        );
+    }
+
+    #[test]
+    fn malformed_links() {
+        assert!(wikilinks("No links [here] or [[]] or [[ ]]").is_empty());
+        assert!(wikilinks("Unterminated [[link").is_empty());
+        assert!(wikilinks("Spans [[two\nlines]]").is_empty());
+        assert_eq!(vec!["b"], wikilinks("Unbalanced [[a [[b]]"));
+    }
+}
+
+#[cfg(test)]
+mod parse_file_tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_note(dir: &tempfile::TempDir, name: &str, contents: &str) -> std::path::PathBuf {
+        let path = dir.path().join(name);
+        let mut fh = fs::File::create(&path).expect("Failed to create note");
+        fh.write_all(contents.as_bytes())
+            .expect("Failed to write note");
+        path
+    }
+
+    #[test]
+    fn yaml_front_matter() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "yaml.md",
+            "---\nauthor: Steve Sosik\ndate: 2021-06-22T12:48:16-0400\ntags:\n- tika\ntitle: YAML note\n---\n\nSome note\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert_eq!("YAML note", doc.title);
+        assert_eq!(vec!["tika"], doc.tags);
+        assert_eq!("yaml.md", doc.filename);
+        assert_eq!("Some note\n", doc.body);
+    }
+
+    #[test]
+    fn subtitle() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "subtitle.md",
+            "---\ndate: 2021-06-22\ntags: tika\ntitle: Lighthouses\nsubtitle: A closer look\n---\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert_eq!("A closer look", doc.subtitle);
+        assert!(!doc.draft);
+    }
+
+    #[test]
+    fn draft() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "draft.md",
+            "+++\ndate = \"2021-06-22\"\ntags = \"tika\"\ntitle = \"Draft\"\ndraft = true\n+++\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert!(doc.draft);
+        assert!(doc.extra.is_empty());
+    }
+
+    #[test]
+    fn extra_fields() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "extra.md",
+            "---\ndate: 2021-06-22\ntags: tika\ntitle: Extra\nproject: tika\npriority: 1\nurgent: true\nrelated:\n- a\n- b\n---\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        let mut extra: Vec<(&str, &str)> = doc
+            .extra
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        extra.sort();
+        assert_eq!(
+            vec![("priority", "1"), ("project", "tika"), ("urgent", "true")],
+            extra
+        );
+
+        let json = serde_json::to_string(&doc).expect("Failed to serialize");
+        let back: TikaDocument = serde_json::from_str(&json).expect("Failed to deserialize");
+        assert_eq!(doc.extra, back.extra);
+
+        // Under their own key in the JSON
+        let value: serde_json::Value = serde_json::from_str(&json).expect("Failed to parse JSON");
+        assert_eq!(Some("tika"), value["fields"]["project"].as_str());
+        assert!(value.get("project").is_none());
+    }
+
+    #[test]
+    fn extra_fields_dont_clash() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "clash.md",
+            "---\ndate: 2021-06-22\ntags: tika\ntitle: Clash\nrank: high\npercent: most\n---\n",
+        );
+        let m = crate::xapian_utils::QueryMatch {
+            doc: parse_file(&path).expect("Failed to parse"),
+            percent: 87,
+            rank: 1,
+        };
+        let value = serde_json::to_value(&m).expect("Failed to serialize");
+        assert_eq!(Some(1), value["rank"].as_u64());
+        assert_eq!(Some(87), value["percent"].as_i64());
+        assert_eq!(Some("high"), value["fields"]["rank"].as_str());
+        assert_eq!(Some("most"), value["fields"]["percent"].as_str());
+    }
+
+    #[test]
+    fn full_path_round_trips_through_json() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "json.md",
+            "---\ndate: 2021-06-22T12:48:16-0400\ntags: tika\ntitle: JSON note\n---\nSome note\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        let json = serde_json::to_string(&doc).expect("Failed to serialize");
+        assert!(json.contains(&format!("\"full_path\":\"{}\"", path.to_str().unwrap())));
+
+        let back: TikaDocument = serde_json::from_str(&json).expect("Failed to deserialize");
+        assert_eq!(path.as_os_str(), back.full_path);
+        assert_eq!(doc, back);
+    }
+
+    #[test]
+    fn body_starts_at_content() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "fenced.md",
+            "---\ndate: 2021-06-22T12:48:16-0400\ntags: tika\ntitle: Fenced\n---\n---\n\n\n# Heading\n\n---\nMore\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert!(doc.body.starts_with("# Heading\n"), "{:?}", doc.body);
+        // Rules within the body are left alone
+        assert!(doc.body.ends_with("\n---\nMore\n"), "{:?}", doc.body);
+    }
+
+    #[test]
+    fn toml_front_matter() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "toml.md",
+            "+++\nauthor = \"Steve Sosik\"\ndate = \"2021-06-22T12:48:16-0400\"\ntags = [\"tika\", \"toml\"]\ntitle = \"TOML note\"\n+++\nSome note\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert_eq!("TOML note", doc.title);
+        assert_eq!("Steve Sosik", doc.author);
+        assert_eq!(vec!["tika", "toml"], doc.tags);
+        assert_eq!("Some note\n", doc.body);
+    }
+
+    #[test]
+    fn toml_bare_dates() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "toml.md",
+            "+++\ndate = 2021-06-22\ntags = \"tika\"\ntitle = \"TOML note\"\nupdated = 2021-07-01T10:00:00Z\n+++\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert_eq!("2021-06-22", doc.date);
+        assert_eq!("20210622", doc.date_str().unwrap());
+        assert_eq!(
+            Some(&String::from("2021-07-01T10:00:00Z")),
+            doc.extra.get("updated")
+        );
+    }
+
+    #[test]
+    fn toml_single_tag() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "toml.md",
+            "+++\ndate = \"2021-06-22T12:48:16-0400\"\ntags = \"tika\"\ntitle = \"TOML note\"\n+++\n",
+        );
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert_eq!(vec!["tika"], doc.tags);
+    }
+
+    #[test]
+    fn missing_title() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "untitled.md",
+            "---\ndate: 2021-06-22T12:48:16-0400\ntags: tika\n---\nSome note\n",
+        );
+        let err = parse_file(&path).unwrap_err().to_string();
+        assert!(err.contains("untitled.md"), "{}", err);
+        assert!(err.contains("title"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_tags() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "tags.md",
+            "---\ndate: 2021-06-22T12:48:16-0400\ntags:\n  nested: map\ntitle: Bad tags\n---\n",
+        );
+        let err = parse_file(&path).unwrap_err().to_string();
+        assert!(err.contains("tags.md"), "{}", err);
+        assert!(err.contains("string or list of strings"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_date() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "date.md",
+            "---\ndate: last tuesday\ntags: tika\ntitle: Bad date\n---\n",
+        );
+        // The rest of the document is still usable
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert_eq!("Bad date", doc.title);
+        let err = doc.parse_date().unwrap_err().to_string();
+        assert!(err.contains("date.md"), "{}", err);
+        assert!(err.contains("last tuesday"), "{}", err);
+    }
+
+    #[test]
+    fn no_front_matter() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "plain.md",
+            "# Just markdown\n\nNo front matter here\n",
+        );
+        let err = parse_file(&path).unwrap_err();
+        assert!(matches!(err, ParseError::NoFrontMatter { .. }), "{}", err);
+        assert!(err.to_string().contains("No YAML or TOML front matter"));
+    }
+
+    #[test]
+    fn non_utf8_filename() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.md");
+        let path = dir.path().join(name);
+        fs::write(&path, "---\ntags: tika\ntitle: Cafe\n---\n").expect("Failed to write note");
+
+        let doc = parse_file(&path).expect("Failed to parse");
+        assert_eq!("caf\u{fffd}.md", doc.filename);
+        assert_eq!(path.as_os_str(), doc.full_path);
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        match parse_file(&dir.path().join("missing.md")) {
+            Err(ParseError::Io { source, .. }) => {
+                assert_eq!(io::ErrorKind::NotFound, source.kind())
+            }
+            other => panic!("Expected an I/O error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn not_utf8() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = dir.path().join("latin1.md");
+        fs::write(&path, b"---\ntitle: Caf\xe9\n---\n").expect("Failed to write note");
+        let err = parse_file(&path).unwrap_err();
+        assert!(matches!(err, ParseError::NotUtf8 { .. }), "{}", err);
+    }
+
+    #[test]
+    fn unparseable_yaml() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = write_note(
+            &dir,
+            "broken.md",
+            "---\ntitle: [unterminated\ntags: tika\n---\nSome note\n",
+        );
+        let err = parse_file(&path).unwrap_err().to_string();
+        assert!(err.contains("broken.md"), "{}", err);
     }
 }
@@ -1,8 +1,12 @@
-use crate::tika_document::TikaDocument;
-use crate::util::event::{Event, Events};
-use crate::xapian_utils;
+use crate::util::event::{Config, Event, Events};
+use crate::util::{load_history, save_history};
+use crate::xapian_utils::{self, MatchOp, QueryMatch, QueryPage, QuerySyntaxError, SortOrder};
 use color_eyre::Report;
+use log::warn;
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::io::{stdout, Write};
+use std::time::{Duration, Instant};
 use termion::{event::Key, raw::IntoRawMode, screen::AlternateScreen};
 use tui::{
     backend::TermionBackend,
@@ -11,6 +15,7 @@ use tui::{
     text::{Span, Spans},
     widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
 };
+use xapian_rusty::{Database, Query};
 
 // Needed to provide `width()` method on String:
 // no method named `width` found for struct `std::string::String` in the current scope
@@ -22,35 +27,252 @@ pub(crate) struct TerminalApp {
     pub(crate) input: String,
     /// Preview window
     pub(crate) output: String,
-    /// Query Matches
-    pub(crate) matches: Vec<TikaDocument>,
-    /// Keep track of which matches are selected
+    /// Query Matches, every page loaded so far
+    pub(crate) matches: Vec<QueryMatch>,
+    /// The input the matches are for, used to fetch further pages
+    pub(crate) matched_input: String,
+    /// MSet offset the next page of matches starts at
+    pub(crate) offset: usize,
+    /// Xapian's estimate of the total number of matches
+    pub(crate) estimated: usize,
+    /// Spelling correction of the matched input, if Xapian has one
+    pub(crate) suggestion: Option<String>,
+    /// Keep track of which match is highlighted
     pub(crate) state: ListState,
+    /// Indices of the matches marked for multi-select
+    pub(crate) selected: HashSet<usize>,
     /// Report query parsing errors back to the user
     pub(crate) errout: String,
     /// Display the parsed query for debugging purposes
     pub(crate) query: String,
+    /// Previously submitted queries, oldest first
+    pub(crate) history: Vec<String>,
+    /// Which history entry is in the input while cycling through the history
+    pub(crate) history_pos: Option<usize>,
+    /// What the input is matched against
+    pub(crate) mode: SearchMode,
+}
+
+/// What the TUI matches the input against
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchMode {
+    /// A full query over every field
+    Query,
+    /// The start of a filename, toggled with Ctrl-f
+    Filename,
+    /// The words of the title only, toggled with Ctrl-t
+    Title,
+}
+
+impl SearchMode {
+    /// Switch to `mode`, or back to full queries if already in it
+    fn toggle(self, mode: SearchMode) -> SearchMode {
+        if self == mode {
+            SearchMode::Query
+        } else {
+            mode
+        }
+    }
+}
+
+/// Fetch the next page once the highlighted match is this close to the end of the loaded matches
+const PREFETCH_MARGIN: usize = 5;
+
+/// The order to list the matches for `input` in. A blank input matches every note equally, so
+/// they're listed most recently modified first instead.
+fn sort_for(input: &str, sort: SortOrder) -> SortOrder {
+    if input.trim().is_empty() {
+        SortOrder::Mtime
+    } else {
+        sort
+    }
+}
+
+/// How many matches to fetch at a time, by default as many as fit in a terminal `height` rows
+/// tall. The match list loses 6 rows to the input, query and status panes, and 2 to its margin.
+fn page_size(limit: Option<usize>, height: u16) -> usize {
+    match limit {
+        Some(limit) => limit,
+        None => (height.saturating_sub(8) as usize).max(1),
+    }
 }
 
 impl TerminalApp {
-    pub fn get_selected(&mut self) -> Vec<String> {
-        let mut ret: Vec<String> = Vec::new();
-        if let Some(i) = self.state.selected() {
-            if let Some(s) = self.matches[i].full_path.to_str() {
-                ret.push(s.into());
+    /// Replace the matches with the first page of results for `input`. A highlight moves back to
+    /// the top, the row it was on now holds a different match, if any.
+    pub fn set_first_page(&mut self, input: &str, page: QueryPage, size: usize) {
+        self.matches.clear();
+        self.offset = 0;
+        self.matched_input = input.to_string();
+        self.append_page(page, size);
+        if self.state.selected().is_some() {
+            self.state.select(if self.matches.is_empty() {
+                None
+            } else {
+                Some(0)
+            });
+        }
+    }
+
+    /// Add the next page of `size` matches for the current query
+    pub fn append_page(&mut self, page: QueryPage, size: usize) {
+        self.matches.extend(page.matches);
+        self.offset += size;
+        self.estimated = page.estimated;
+    }
+
+    /// Whether the highlighted match is near enough the end of the loaded matches that the next
+    /// page should be fetched
+    pub fn wants_next_page(&self) -> bool {
+        match self.state.selected() {
+            Some(i) => i + PREFETCH_MARGIN >= self.matches.len() && self.offset < self.estimated,
+            None => false,
+        }
+    }
+
+    /// Summary of how many matches are loaded, e.g. "showing 1–40 of ~320", followed by any
+    /// spelling suggestion. Filename and title modes are called out up front.
+    pub fn status(&self) -> String {
+        let mode = match self.mode {
+            SearchMode::Query => "",
+            SearchMode::Filename => "filename mode · ",
+            SearchMode::Title => "title mode · ",
+        };
+        let status = if self.matches.is_empty() {
+            String::from("no matches")
+        } else {
+            format!(
+                "showing 1–{} of ~{}",
+                self.matches.len(),
+                self.estimated.max(self.matches.len())
+            )
+        };
+        match &self.suggestion {
+            Some(suggestion) => format!("{}{} · Did you mean: {}", mode, status, suggestion),
+            None => format!("{}{}", mode, status),
+        }
+    }
+
+    /// Paths of every marked match, or of the highlighted match when nothing is marked. Indices
+    /// left over from a longer list of matches are ignored. Paths that aren't UTF-8 are kept as
+    /// they are.
+    pub fn get_selected(&mut self) -> Vec<OsString> {
+        let mut indices: Vec<usize> = self.selected.iter().copied().collect();
+        indices.sort_unstable();
+        if indices.is_empty() {
+            indices.extend(self.state.selected());
+        }
+
+        indices
+            .into_iter()
+            .filter_map(|i| self.matches.get(i))
+            .map(|m| m.doc.full_path.clone())
+            .collect()
+    }
+
+    /// Parse `input` as a full query, in filename mode as the start of a filename, or in title mode
+    /// as words of the title. A blank input matches every note in any mode.
+    fn parse_input(
+        &self,
+        input: &str,
+        lang: &str,
+        flags: i16,
+        default_op: MatchOp,
+        db: &mut Database,
+    ) -> Result<Query, Report> {
+        if input.trim().is_empty() {
+            return xapian_utils::match_all_query();
+        }
+        match self.mode {
+            SearchMode::Query => {
+                xapian_utils::parse_user_query_with_flags(input, lang, flags, default_op, Some(db))
+            }
+            SearchMode::Filename => xapian_utils::filename_query(input, lang, db),
+            SearchMode::Title => xapian_utils::title_query(input, lang, flags, Some(db)),
+        }
+    }
+
+    /// Report `e` on the status line. A syntax error also puts a caret under the offending
+    /// character of the input, in place of the parsed query.
+    fn show_error(&mut self, e: &Report) {
+        match e.downcast_ref::<QuerySyntaxError>() {
+            Some(e) => {
+                self.errout = e.summary();
+                self.query = e.caret();
             }
+            None => self.errout = e.to_string(),
+        }
+    }
+
+    /// Replace the input with the previous, older, history entry
+    pub fn history_previous(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+        let pos = match self.history_pos {
+            Some(i) => i.saturating_sub(1),
+            None => self.history.len() - 1,
         };
-        ret
+        self.history_pos = Some(pos);
+        self.input = self.history[pos].clone();
     }
 
-    pub fn get_selected_contents(&mut self) -> String {
+    /// Replace the input with the next, newer, history entry. Moving past the newest entry leaves
+    /// the input empty.
+    pub fn history_next(&mut self) {
+        match self.history_pos {
+            Some(i) if i + 1 < self.history.len() => {
+                self.history_pos = Some(i + 1);
+                self.input = self.history[i + 1].clone();
+            }
+            Some(_) => {
+                self.history_pos = None;
+                self.input.clear();
+            }
+            None => {}
+        }
+    }
+
+    /// Add the input to the history, unless it's empty or repeats the latest entry
+    pub fn record_history(&mut self) {
+        let query = self.input.trim();
+        if !query.is_empty() && self.history.last().map(String::as_str) != Some(query) {
+            self.history.push(query.to_string());
+        }
+    }
+
+    /// Delete the last word of the input, along with any whitespace after it
+    pub fn delete_word(&mut self) {
+        let trimmed = self.input.trim_end().len();
+        let word_start = self.input[..trimmed]
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + 1);
+        self.input.truncate(word_start);
+    }
+
+    /// Mark or unmark the highlighted match
+    pub fn toggle_selected(&mut self) {
         if let Some(i) = self.state.selected() {
-            return self.matches[i].body.clone();
-        };
-        String::from("")
+            if !self.selected.remove(&i) {
+                self.selected.insert(i);
+            }
+        }
     }
 
+    pub fn get_selected_contents(&mut self) -> String {
+        match self.state.selected().and_then(|i| self.matches.get(i)) {
+            Some(m) => m.doc.body.clone(),
+            None => String::new(),
+        }
+    }
+
+    /// Highlight the next match, wrapping around to the first. Nothing is highlighted when there
+    /// are no matches.
     pub fn next(&mut self) {
+        if self.matches.is_empty() {
+            self.state.select(None);
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i >= self.matches.len() - 1 {
@@ -64,7 +286,13 @@ impl TerminalApp {
         self.state.select(Some(i));
     }
 
+    /// Highlight the previous match, wrapping around to the last. Nothing is highlighted when
+    /// there are no matches.
     pub fn previous(&mut self) {
+        if self.matches.is_empty() {
+            self.state.select(None);
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
@@ -85,9 +313,17 @@ impl Default for TerminalApp {
             input: String::new(),
             output: String::new(),
             matches: Vec::new(),
+            matched_input: String::new(),
+            offset: 0,
+            estimated: 0,
+            suggestion: None,
             state: ListState::default(),
+            selected: HashSet::new(),
             errout: String::new(),
             query: String::new(),
+            history: Vec::new(),
+            history_pos: None,
+            mode: SearchMode::Query,
         }
     }
 }
@@ -109,21 +345,180 @@ pub fn setup_panic() {
     }));
 }
 
-/// Interactive query interface
-pub fn interactive_query() -> Result<Vec<String>, Report> {
-    // TODO create DB in main and pass it through to query_db
+/// The lowercased words of `input` worth highlighting in matches: operator keywords, field names
+/// such as `author:` and punctuation are dropped
+fn highlight_words(input: &str) -> Vec<String> {
+    input
+        .split_whitespace()
+        // Only the value of a field, e.g. `steve` from `author:steve`
+        .map(|chunk| chunk.rsplit(':').next().unwrap_or(chunk))
+        .filter(|chunk| xapian_utils::matchop(&format!("{} ;", chunk)).is_err())
+        .flat_map(|chunk| chunk.split(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// The tags shown after a match's title, e.g. ` [rust, notes]`, cut short with an ellipsis to fit
+/// within `room` columns. None when there are no tags or not even a single character fits.
+fn tags_label(tags: &[String], room: usize) -> Option<String> {
+    if tags.is_empty() {
+        return None;
+    }
+    let label = format!(" [{}]", tags.join(", "));
+    if label.width() <= room {
+        return Some(label);
+    }
+    // Room for the leading " [", at least one character, and the closing "…]"
+    if room < 5 {
+        return None;
+    }
+    let mut truncated = String::from(" [");
+    for c in label.chars().skip(2) {
+        if truncated.width() + c.to_string().width() + 2 > room {
+            break;
+        }
+        truncated.push(c);
+    }
+    truncated.push_str("…]");
+    Some(truncated)
+}
+
+/// Split `text` into spans, styling each word that starts with one of `words` with `style`.
+/// Matching on the start of the word covers partial words as well as most inflections the stemmer
+/// would match, e.g. "note" highlights "Notes".
+fn highlight_spans<'a>(text: &'a str, words: &[String], style: Style) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    let mut plain_start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if !c.is_alphanumeric() {
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        while let Some(&(i, c)) = chars.peek() {
+            if !c.is_alphanumeric() {
+                break;
+            }
+            end = i + c.len_utf8();
+            chars.next();
+        }
+
+        let word = text[start..end].to_lowercase();
+        if words.iter().any(|w| word.starts_with(w.as_str())) {
+            if plain_start < start {
+                spans.push(Span::raw(&text[plain_start..start]));
+            }
+            spans.push(Span::styled(&text[start..end], style));
+            plain_start = end;
+        }
+    }
+    if plain_start < text.len() {
+        spans.push(Span::raw(&text[plain_start..]));
+    }
+    spans
+}
+
+/// Paths chosen in the interactive query interface
+pub struct Selection {
+    pub paths: Vec<OsString>,
+    /// Open the paths in an editor rather than printing them
+    pub edit: bool,
+}
+
+/// Settings for an `interactive_query` session
+#[derive(Debug, Clone)]
+pub struct InteractiveOptions {
+    /// QueryParser feature flags, see `xapian_utils::query_flags`
+    pub flags: i16,
+    /// How the words of a query are joined, AND or OR
+    pub default_op: MatchOp,
+    pub sort: SortOrder,
+    /// Matches are fetched this many at a time as the highlight nears the end of the list,
+    /// without a limit as many as fit in the terminal
+    pub limit: Option<usize>,
+    /// Queries are re-run once typing pauses for this long
+    pub debounce: Duration,
+    /// How often to wake up while waiting for typing to pause, by default half the `debounce`.
+    /// There are no wake-ups at all while idle.
+    pub tick_rate: Option<Duration>,
+    /// Template each match is listed with, see `TikaDocument::format`
+    pub result_format: String,
+    /// Where submitted queries are remembered
+    pub history_path: String,
+    /// Start out matching titles only, see `SearchMode::Title`
+    pub title_only: bool,
+}
+
+/// Interactive query interface over the database at `db_path`, with queries stemmed for `lang`
+pub fn interactive_query(
+    db_path: &str,
+    lang: &str,
+    opts: &InteractiveOptions,
+) -> Result<Selection, Report> {
+    let InteractiveOptions {
+        flags,
+        default_op,
+        sort,
+        limit,
+        debounce,
+        tick_rate,
+        ref result_format,
+        ref history_path,
+        title_only,
+    } = *opts;
+    // Open the DB once up front and reuse the handle for every keystroke
+    let mut db = xapian_utils::open_db(db_path)?;
+
     let mut tui = tui::Terminal::new(TermionBackend::new(AlternateScreen::from(
         stdout().into_raw_mode().unwrap(),
     )))
     .unwrap();
 
-    // Setup event handlers
-    let events = Events::new();
+    // Setup event handlers, ticking often enough to notice when the debounce interval has passed
+    let events = Events::with_config(Config {
+        tick_rate: tick_rate.unwrap_or_else(|| (debounce / 2).max(Duration::from_millis(10))),
+    });
 
     // Create default app state
-    let mut app = TerminalApp::default();
+    let mut app = TerminalApp {
+        history: load_history(history_path)?,
+        mode: if title_only {
+            SearchMode::Title
+        } else {
+            SearchMode::Query
+        },
+        ..TerminalApp::default()
+    };
+
+    // Terms stemmed differently at index and query time won't match, give a heads up
+    match xapian_utils::indexed_language(&mut db)? {
+        Some(indexed) if indexed != lang => {
+            app.errout = format!(
+                "Warning: index was built with stemmer language '{}' but querying with '{}'",
+                indexed, lang
+            );
+        }
+        _ => {}
+    }
+    // Like fzf, start out listing every note rather than nothing, most recently modified first.
+    // An empty database has nothing to list, leave the status line saying so.
+    if db.get_doccount()? > 0 {
+        let size = page_size(limit, tui.size()?.height);
+        let mut query = app.parse_input("", lang, flags, default_op, &mut db)?;
+        app.query = query.get_description();
+        let page = xapian_utils::query_db_page(&mut db, query, sort_for("", sort), 0, size)?;
+        app.set_first_page("", page, size);
+    }
+
+    let mut edit = false;
+    // When the input was last edited, if the query hasn't been re-run since
+    let mut last_edit: Option<Instant> = None;
 
     loop {
+        // Fetch a page of matches at a time
+        let page_size = page_size(limit, tui.size()?.height);
+
         // Draw UI
         tui.draw(|f| {
             let panes = Layout::default()
@@ -147,13 +542,40 @@ pub fn interactive_query() -> Result<Vec<String>, Report> {
                 .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
                 .split(panes[0]);
 
-            // Output area where match titles are displayed
+            // Output area where the matches are listed, rendered with `result_format`
+            // Highlight the words of the query the matches are for within each row
+            let words = highlight_words(&app.matched_input);
+            let highlight_style = Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD);
+            let tags_style = Style::default().fg(Color::DarkGray);
+            let rows: Vec<String> = app
+                .matches
+                .iter()
+                .map(|m| m.doc.format(result_format))
+                .collect();
             let matches: Vec<ListItem> = app
                 .matches
                 .iter()
-                .map(|m| {
-                    let content = vec![Spans::from(Span::raw(format!("{}", m.title)))];
-                    ListItem::new(content)
+                .zip(rows.iter())
+                .enumerate()
+                .map(|(i, (m, row))| {
+                    let marker = if app.selected.contains(&i) {
+                        "✓ "
+                    } else {
+                        "  "
+                    };
+                    let prefix = format!("{}{:>3}% ", marker, m.percent);
+                    // Whatever is left of the line after the border, highlight symbol, prefix and
+                    // text is room for the tags
+                    let room = (content[0].width as usize)
+                        .saturating_sub(3 + prefix.width() + row.width());
+                    let mut spans = vec![Span::raw(prefix)];
+                    spans.extend(highlight_spans(row, &words, highlight_style));
+                    if let Some(tags) = tags_label(&m.doc.tags, room) {
+                        spans.push(Span::styled(tags, tags_style));
+                    }
+                    ListItem::new(vec![Spans::from(spans)])
                 })
                 .collect();
             let matches = List::new(matches)
@@ -168,9 +590,14 @@ pub fn interactive_query() -> Result<Vec<String>, Report> {
                 .wrap(Wrap { trim: true });
             f.render_widget(paragraph, content[1]);
 
-            // Input area where queries are entered
+            // Input area where queries are entered, red while the query has an error
+            let input_color = if app.errout.is_empty() {
+                Color::Yellow
+            } else {
+                Color::Red
+            };
             let input = Paragraph::new(app.input.as_ref())
-                .style(Style::default().fg(Color::Yellow))
+                .style(Style::default().fg(input_color))
                 .block(Block::default().borders(Borders::NONE));
             f.render_widget(input, panes[1]);
 
@@ -182,63 +609,408 @@ pub fn interactive_query() -> Result<Vec<String>, Report> {
                 panes[1].y,
             );
 
-            // Area to display the parsed Xapian::Query.get_description()
+            // Area to display the parsed Xapian::Query.get_description(), or a caret under where
+            // the input stopped parsing
+            let query_color = if app.errout.is_empty() {
+                Color::Green
+            } else {
+                Color::Red
+            };
             let query = Paragraph::new(app.query.as_ref())
-                .style(Style::default().fg(Color::Green))
+                .style(Style::default().fg(query_color))
                 .block(Block::default().borders(Borders::NONE));
             f.render_widget(query, panes[2]);
 
-            // Area where errors are displayed, query parsing errors, etc
-            let errout = Paragraph::new(app.errout.as_ref())
-                .style(Style::default().fg(Color::Red))
-                .block(Block::default().borders(Borders::NONE));
-            f.render_widget(errout, panes[3]);
+            // Status line with the match counts, replaced by errors such as query parsing errors
+            let status = if app.errout.is_empty() {
+                Paragraph::new(app.status()).style(Style::default().fg(Color::Gray))
+            } else {
+                Paragraph::new(app.errout.as_ref()).style(Style::default().fg(Color::Red))
+            };
+            f.render_widget(
+                status.block(Block::default().borders(Borders::NONE)),
+                panes[3],
+            );
         })?;
 
+        // Ticks are only needed to notice typing pausing, otherwise wait on input alone
+        events.set_ticking(last_edit.is_some());
+
         // Handle input
         if let Event::Input(input) = events.next()? {
+            let previous_input = app.input.clone();
             match input {
                 Key::Char('\n') => {
                     // Select choice
+                    app.record_history();
+                    break;
+                }
+                Key::Ctrl('o') => {
+                    // Open choice in the editor
+                    app.record_history();
+                    edit = true;
                     break;
                 }
+                Key::Alt('p') => {
+                    app.history_previous();
+                }
+                Key::Alt('n') => {
+                    app.history_next();
+                }
                 Key::Ctrl('c') => {
                     break;
                 }
+                Key::Char('\t') => {
+                    // Mark the highlighted match for multi-select. Space is left alone since it's
+                    // needed for multi-word queries.
+                    app.toggle_selected();
+                }
                 Key::Char(c) => {
                     app.input.push(c);
                 }
                 Key::Backspace => {
                     app.input.pop();
                 }
+                Key::Ctrl('u') => {
+                    app.input.clear();
+                }
+                Key::Ctrl('w') => {
+                    app.delete_word();
+                }
+                Key::Ctrl('f') => {
+                    // Toggle filename mode, the input is re-run in the new mode
+                    app.mode = app.mode.toggle(SearchMode::Filename);
+                    last_edit = Some(Instant::now());
+                }
+                Key::Ctrl('t') => {
+                    app.mode = app.mode.toggle(SearchMode::Title);
+                    last_edit = Some(Instant::now());
+                }
                 Key::Down | Key::Ctrl('n') => {
+                    if app.wants_next_page() {
+                        let result = app
+                            .parse_input(&app.matched_input, lang, flags, default_op, &mut db)
+                            .and_then(|query| {
+                                xapian_utils::query_db_page(
+                                    &mut db,
+                                    query,
+                                    sort_for(&app.matched_input, sort),
+                                    app.offset,
+                                    page_size,
+                                )
+                            });
+                        match result {
+                            Ok(page) => app.append_page(page, page_size),
+                            Err(e) => app.show_error(&e),
+                        }
+                    }
                     app.next();
                     app.output = app.get_selected_contents();
                 }
-                Key::Up | Key::Ctrl('p') => {
+                // Ctrl-j can't be told apart from Enter, so only Ctrl-k pairs with Ctrl-n
+                Key::Up | Key::Ctrl('p') | Key::Ctrl('k') => {
                     app.previous();
                     app.output = app.get_selected_contents();
                 }
                 _ => {}
             }
 
-            let mut inp: String = app.input.to_owned();
-            // Add a trailing ` ;` to the query to hint to Nom that it has a "full" string
-            inp.push_str(&" ;");
+            // Only re-run the query when it changed, navigating and marking keep the results
+            if app.input != previous_input {
+                last_edit = Some(Instant::now());
+            }
+        }
 
-            match xapian_utils::parse_user_query(&inp) {
-                Ok(mut query) => {
-                    app.query = query.get_description();
-                    app.matches = xapian_utils::query_db(query)?;
-                }
-                Err(e) => {
-                    app.errout = e.to_string();
-                }
-            };
+        // Wait for typing to pause before running the query
+        match last_edit {
+            Some(t) if t.elapsed() >= debounce => last_edit = None,
+            _ => continue,
         }
+
+        let result = app
+            .parse_input(&app.input, lang, flags, default_op, &mut db)
+            .and_then(|mut query| {
+                app.query = query.get_description();
+                xapian_utils::query_db_page(
+                    &mut db,
+                    query,
+                    sort_for(&app.input, sort),
+                    0,
+                    page_size,
+                )
+            });
+        match result {
+            Ok(page) => {
+                let input = app.input.clone();
+                app.set_first_page(&input, page, page_size);
+                app.output = app.get_selected_contents();
+                app.suggestion = if app.mode == SearchMode::Filename {
+                    None
+                } else {
+                    xapian_utils::spelling_suggestion(&mut db, &input, lang).unwrap_or(None)
+                };
+                // Marked indices refer to the previous result set
+                app.selected.clear();
+                app.errout.clear();
+            }
+            Err(e) => {
+                // Keep the session and the previous matches around so the query can be fixed
+                app.show_error(&e);
+            }
+        };
     }
 
     tui.clear().unwrap();
 
-    Ok(app.get_selected())
+    if let Err(e) = save_history(history_path, &app.history) {
+        warn!("Failed to save query history to {}: {}", history_path, e);
+    }
+
+    Ok(Selection {
+        paths: app.get_selected(),
+        edit,
+    })
+}
+
+#[cfg(test)]
+mod terminal_app_tests {
+    use super::*;
+    use crate::tika_document::TikaDocument;
+
+    fn app_with_matches(paths: &[&str]) -> TerminalApp {
+        TerminalApp {
+            matches: paths
+                .iter()
+                .map(|p| QueryMatch {
+                    doc: TikaDocument {
+                        full_path: OsString::from(p),
+                        ..TikaDocument::default()
+                    },
+                    ..QueryMatch::default()
+                })
+                .collect(),
+            ..TerminalApp::default()
+        }
+    }
+
+    #[test]
+    fn blank_input_by_mtime() {
+        assert_eq!(SortOrder::Mtime, sort_for("", SortOrder::Relevance));
+        assert_eq!(SortOrder::Mtime, sort_for("  ", SortOrder::Relevance));
+        assert_eq!(SortOrder::Relevance, sort_for("rust", SortOrder::Relevance));
+        assert_eq!(40, page_size(None, 48));
+        assert_eq!(1, page_size(None, 4));
+        assert_eq!(10, page_size(Some(10), 48));
+    }
+
+    #[test]
+    fn highlighted_when_nothing_marked() {
+        let mut app = app_with_matches(&["a.md", "b.md"]);
+        app.next();
+        assert_eq!(vec!["a.md"], app.get_selected());
+    }
+
+    #[test]
+    fn marked_matches_in_list_order() {
+        let mut app = app_with_matches(&["a.md", "b.md", "c.md"]);
+        app.state.select(Some(2));
+        app.toggle_selected();
+        app.state.select(Some(0));
+        app.toggle_selected();
+        assert_eq!(vec!["a.md", "c.md"], app.get_selected());
+
+        // Toggling again unmarks
+        app.toggle_selected();
+        assert_eq!(vec!["c.md"], app.get_selected());
+    }
+
+    #[test]
+    fn non_utf8_path_selected() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let path = OsString::from_vec(b"/notes/caf\xe9.md".to_vec());
+        let mut app = app_with_matches(&["a.md"]);
+        app.matches[0].doc.full_path = path.clone();
+        app.next();
+        assert_eq!(vec![path], app.get_selected());
+    }
+
+    fn page(paths: &[&str], estimated: usize) -> QueryPage {
+        QueryPage {
+            matches: app_with_matches(paths).matches,
+            estimated,
+        }
+    }
+
+    #[test]
+    fn paging() {
+        let mut app = TerminalApp::default();
+        assert_eq!("no matches", app.status());
+
+        app.set_first_page("foo", page(&["a.md", "b.md"], 4), 2);
+        assert_eq!("showing 1–2 of ~4", app.status());
+        assert!(!app.wants_next_page());
+
+        // Highlighting near the end of the loaded matches asks for more
+        app.next();
+        assert!(app.wants_next_page());
+        app.append_page(page(&["c.md", "d.md"], 4), 2);
+        assert_eq!("showing 1–4 of ~4", app.status());
+        assert_eq!(4, app.offset);
+        assert!(!app.wants_next_page());
+
+        // A new query starts over
+        app.set_first_page("bar", page(&["e.md"], 1), 2);
+        assert_eq!("showing 1–1 of ~1", app.status());
+        assert_eq!("bar", app.matched_input);
+
+        app.suggestion = Some(String::from("baz"));
+        assert_eq!("showing 1–1 of ~1 · Did you mean: baz", app.status());
+
+        app.suggestion = None;
+        app.mode = SearchMode::Filename;
+        assert_eq!("filename mode · showing 1–1 of ~1", app.status());
+
+        app.mode = app.mode.toggle(SearchMode::Title);
+        assert_eq!("title mode · showing 1–1 of ~1", app.status());
+        app.mode = app.mode.toggle(SearchMode::Title);
+        assert_eq!("showing 1–1 of ~1", app.status());
+    }
+
+    #[test]
+    fn highlight_reset_on_new_matches() {
+        let mut app = TerminalApp::default();
+        app.set_first_page("foo", page(&["a.md", "b.md", "c.md", "d.md"], 4), 10);
+        assert_eq!(None, app.state.selected());
+
+        app.state.select(Some(3));
+        app.set_first_page("foo bar", page(&["a.md", "b.md"], 2), 10);
+        assert_eq!(Some(0), app.state.selected());
+
+        app.set_first_page("foo bar baz", page(&[], 0), 10);
+        assert_eq!(None, app.state.selected());
+    }
+
+    #[test]
+    fn syntax_error_caret() {
+        let mut app = TerminalApp::default();
+        let e = xapian_utils::parse_query_expr("foo AND (bar").expect_err("Parsed");
+        app.show_error(&e);
+        assert_eq!("Expected ')' at column 13", app.errout);
+        assert_eq!("            ^", app.query);
+
+        app.show_error(&eyre::eyre!("Database is locked"));
+        assert_eq!("Database is locked", app.errout);
+    }
+
+    #[test]
+    fn selection_after_matches_shrink() {
+        let mut app = app_with_matches(&["/notes/a.md", "/notes/b.md", "/notes/c.md"]);
+        app.state.select(Some(2));
+        app.selected.insert(1);
+
+        // A refined query leaves fewer matches than the stale indices point at
+        app.matches = app_with_matches(&["/notes/d.md"]).matches;
+        assert_eq!(Vec::<OsString>::new(), app.get_selected());
+        assert_eq!("", app.get_selected_contents());
+    }
+
+    #[test]
+    fn navigate_empty_and_single() {
+        let mut app = TerminalApp::default();
+        app.next();
+        assert_eq!(None, app.state.selected());
+        app.previous();
+        assert_eq!(None, app.state.selected());
+
+        let mut app = app_with_matches(&["/notes/a.md"]);
+        app.next();
+        assert_eq!(Some(0), app.state.selected());
+        app.next();
+        assert_eq!(Some(0), app.state.selected());
+        app.previous();
+        assert_eq!(Some(0), app.state.selected());
+    }
+
+    #[test]
+    fn delete_word() {
+        let mut app = TerminalApp {
+            input: String::from("tag:rust AND  steve  "),
+            ..TerminalApp::default()
+        };
+        app.delete_word();
+        assert_eq!("tag:rust AND  ", app.input);
+        app.delete_word();
+        assert_eq!("tag:rust ", app.input);
+        app.delete_word();
+        assert_eq!("", app.input);
+        app.delete_word();
+        assert_eq!("", app.input);
+    }
+
+    #[test]
+    fn history() {
+        let mut app = TerminalApp::default();
+        app.history_previous();
+        assert_eq!("", app.input);
+
+        for query in &["foo", "foo", " ", "bar"] {
+            app.input = query.to_string();
+            app.record_history();
+        }
+        assert_eq!(vec!["foo", "bar"], app.history);
+
+        app.input.clear();
+        app.history_previous();
+        assert_eq!("bar", app.input);
+        app.history_previous();
+        assert_eq!("foo", app.input);
+        // The oldest entry stays put
+        app.history_previous();
+        assert_eq!("foo", app.input);
+        app.history_next();
+        assert_eq!("bar", app.input);
+        app.history_next();
+        assert_eq!("", app.input);
+    }
+
+    #[test]
+    fn words_to_highlight() {
+        // Operator keywords are dropped even within a phrase, they aren't worth highlighting
+        assert_eq!(
+            vec!["rust", "steve", "this", "that"],
+            highlight_words(r#"tag:rust AND (author:steve OR "this and that")"#)
+        );
+        assert!(highlight_words("").is_empty());
+    }
+
+    #[test]
+    fn highlighted_title() {
+        let style = Style::default().add_modifier(Modifier::BOLD);
+        let words = highlight_words("note OR rust");
+        let spans = highlight_spans("Notes on Rust, trusted", &words, style);
+        assert_eq!(
+            vec![
+                Span::styled("Notes", style),
+                Span::raw(" on "),
+                Span::styled("Rust", style),
+                Span::raw(", trusted"),
+            ],
+            spans
+        );
+
+        assert_eq!(
+            vec![Span::raw("Unrelated")],
+            highlight_spans("Unrelated", &words, style)
+        );
+    }
+
+    #[test]
+    fn tags_after_title() {
+        let tags = vec!["rust".to_string(), "notes".to_string()];
+        assert_eq!(Some(" [rust, notes]".to_string()), tags_label(&tags, 80));
+        assert_eq!(Some(" [rust, n…]".to_string()), tags_label(&tags, 11));
+        assert_eq!(None, tags_label(&tags, 4));
+        assert_eq!(None, tags_label(&[], 80));
+    }
 }
@@ -1,46 +1,488 @@
-use glob::{glob, Paths};
-use std::{fs, io, io::Read, path::Path};
-use toml::Value as tomlVal;
+use crate::xapian_utils::{MatchOp, DEFAULT_OPERATOR};
+use color_eyre::Report;
+use directories::ProjectDirs;
+use eyre::{eyre, WrapErr};
+use glob::{glob, GlobResult, Pattern};
+use log::info;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::Infallible;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::process::CommandExt;
+use std::time::{Duration, SystemTime};
+use std::{env, fs, io, path::Path, process::Command};
 
-pub(crate) fn glob_files(
-    cfg_file: &str,
+/// Where the config file is read from when the platform config directory can't be resolved
+pub const DEFAULT_CONFIG_FILE: &str = "~/.config/tika/tika.toml";
+/// Where queries submitted in the TUI are remembered when the platform data directory can't be
+/// resolved
+pub const DEFAULT_HISTORY_PATH: &str = "~/.config/tika/history";
+/// How many of the most recent queries are kept in the history file
+const HISTORY_LIMIT: usize = 1000;
+/// Where the Xapian database lives when the platform data directory can't be resolved
+pub const DEFAULT_DB_PATH: &str = "~/.config/tika/db";
+/// How long typing has to pause before the TUI re-runs the query, unless `debounce-ms` is set
+pub const DEFAULT_DEBOUNCE_MS: u64 = 150;
+/// How many times more a term in a Markdown heading counts than one in the body text, unless
+/// `heading-weight` is set
+pub const DEFAULT_HEADING_WEIGHT: u32 = 5;
+/// How many documents are indexed between commits unless `commit-batch-size` is set
+pub const DEFAULT_COMMIT_BATCH_SIZE: usize = 1000;
+/// How each match is rendered in the TUI unless `result-format` is set, see `TikaDocument::format`
+pub const DEFAULT_RESULT_FORMAT: &str = "{title}";
+/// Maximum number of matches the query subcommand returns unless `--limit` or `result-limit` is set
+pub const DEFAULT_RESULT_LIMIT: usize = 100;
+/// Stemmer language used for indexing and querying unless `--lang` or `stemmer-language` is set
+pub const DEFAULT_STEMMER_LANGUAGE: &str = "en";
+
+/// What `config init` writes, the keys most setups need with example values to edit
+pub const DEFAULT_CONFIG: &str = r#"# tika config, see `tika --help` for the CLI arguments that override these keys
+
+# Markdown notes to index, a single glob or a list of them
+source-glob = "~/notes/**/*.md"
+
+# Paths to leave out of the index even though they match source-glob
+exclude-glob = ["~/notes/templates/*"]
+
+# Where the Xapian database is kept, by default under the platform data directory, e.g.
+# ~/.local/share/tika/db
+# db-path = "~/notes/.tika-db"
+
+# Stemmer language for indexing and querying, reindex after changing it
+stemmer-language = "en"
+
+# How the words of a query are joined, AND for notes with all of them or OR for any
+# default-operator = "OR"
+"#;
+
+/// The TOML config file, read once at startup and passed around by reference. Every key is
+/// optional, the accessors fill in defaults and give CLI arguments precedence. Unknown keys are
+/// errors, so a typo such as `source_glob` doesn't go unnoticed.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Where the config was read from, to name in error messages
+    #[serde(skip)]
+    file: String,
+    #[serde(rename = "source-glob")]
+    source_globs: Option<Globs>,
+    #[serde(rename = "exclude-glob")]
+    exclude_globs: Vec<String>,
+    db_path: Option<String>,
+    stemmer_language: Option<String>,
+    default_operator: Option<String>,
+    query_flags: BTreeMap<String, bool>,
+    synonyms: BTreeMap<String, Vec<String>>,
+    debounce_ms: Option<u64>,
+    tick_ms: Option<u64>,
+    heading_weight: Option<u32>,
+    commit_batch_size: Option<usize>,
+    result_limit: Option<usize>,
+    result_format: Option<String>,
+    editor_cmd: Option<String>,
+}
+
+/// `source-glob`, a single glob or a list of them
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum Globs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Config {
+    /// Read and parse `cfg_file`. A missing file is an empty config, every key has a default or a
+    /// CLI argument to take its place.
+    pub fn load(cfg_file: &str) -> Result<Config, Report> {
+        let contents = match fs::read_to_string(cfg_file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to read config file {}", cfg_file))
+            }
+        };
+        let cfg: Config = toml::from_str(&contents)
+            .wrap_err_with(|| format!("Failed to parse config file {}", cfg_file))?;
+        Ok(Config {
+            file: cfg_file.to_string(),
+            ..cfg
+        })
+    }
+
+    /// The `source-glob` key, which is required unless `-s` is given instead
+    fn source_globs(&self) -> Result<Vec<&str>, Report> {
+        match &self.source_globs {
+            Some(Globs::One(glob)) => Ok(vec![glob.as_str()]),
+            Some(Globs::Many(globs)) => Ok(globs.iter().map(String::as_str).collect()),
+            None => Err(eyre!(
+                "No 'source-glob' key in config file {} and no -s given",
+                self.file
+            )),
+        }
+    }
+
+    /// Resolve the database path, in order of precedence: the `--db` CLI argument, the `db-path`
+    /// key, and finally `default_db_path`. The result is tilde-expanded.
+    pub fn db_path(&self, db: Option<&str>) -> String {
+        match db.or_else(|| self.db_path.as_deref()) {
+            Some(path) => shellexpand::tilde(path).into_owned(),
+            None => default_db_path(),
+        }
+    }
+
+    /// The `debounce-ms` key, or `DEFAULT_DEBOUNCE_MS`
+    pub fn debounce_interval(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS))
+    }
+
+    /// The `tick-ms` key, how often the TUI wakes up while waiting for typing to pause. None when
+    /// it isn't set, the TUI then picks a rate to suit the debounce interval.
+    pub fn tick_rate(&self) -> Option<Duration> {
+        self.tick_ms.map(|ms| Duration::from_millis(ms.max(1)))
+    }
+
+    /// The `heading-weight` key, or `DEFAULT_HEADING_WEIGHT`
+    pub fn heading_weight(&self) -> u32 {
+        self.heading_weight.unwrap_or(DEFAULT_HEADING_WEIGHT).max(1)
+    }
+
+    /// The `commit-batch-size` key, or `DEFAULT_COMMIT_BATCH_SIZE`
+    pub fn commit_batch_size(&self) -> usize {
+        self.commit_batch_size
+            .unwrap_or(DEFAULT_COMMIT_BATCH_SIZE)
+            .max(1)
+    }
+
+    /// The `--limit` CLI argument, falling back to the `result-limit` key. None when neither is
+    /// set, so callers can pick a default to suit, e.g. the terminal height.
+    pub fn result_limit(&self, limit: Option<usize>) -> Option<usize> {
+        limit.or(self.result_limit)
+    }
+
+    /// The `result-format` key, a template such as `{date} {title}` that `TikaDocument::format`
+    /// renders each match with. None when it isn't set.
+    pub fn result_format(&self) -> Option<String> {
+        self.result_format.clone()
+    }
+
+    /// Resolve the stemmer language, in order of precedence: the `--lang` CLI argument, the
+    /// `stemmer-language` key, and finally `DEFAULT_STEMMER_LANGUAGE`
+    pub fn stemmer_language(&self, lang: Option<&str>) -> String {
+        lang.or_else(|| self.stemmer_language.as_deref())
+            .unwrap_or(DEFAULT_STEMMER_LANGUAGE)
+            .to_string()
+    }
+
+    /// The `default-operator` key, how the words of a query are joined: `AND` for the notes with
+    /// all of them, `OR` for the notes with any. Falls back to `DEFAULT_OPERATOR`.
+    pub fn default_operator(&self) -> Result<MatchOp, Report> {
+        match self
+            .default_operator
+            .as_deref()
+            .map(str::to_uppercase)
+            .as_deref()
+        {
+            None => Ok(DEFAULT_OPERATOR),
+            Some("AND") => Ok(MatchOp::And),
+            Some("OR") => Ok(MatchOp::Or),
+            Some(_) => Err(eyre!(
+                "Invalid default-operator '{}' in config file {}, expected AND or OR",
+                self.default_operator.as_deref().unwrap_or_default(),
+                self.file
+            )),
+        }
+    }
+
+    /// The `[query-flags]` table as (flag name, enabled) pairs, e.g. `partial = false`. Flags left
+    /// out keep their default, see `xapian_utils::query_flags`.
+    pub fn query_flags(&self) -> Vec<(String, bool)> {
+        self.query_flags
+            .iter()
+            .map(|(name, enabled)| (name.clone(), *enabled))
+            .collect()
+    }
+
+    /// The `[synonyms]` table as (term, synonyms) pairs, e.g. `kubernetes = ["k8s", "kube"]`
+    pub fn synonyms(&self) -> Vec<(String, Vec<String>)> {
+        self.synonyms
+            .iter()
+            .map(|(term, synonyms)| (term.clone(), synonyms.clone()))
+            .collect()
+    }
+
+    /// The `--editor-cmd` CLI argument, falling back to the `editor-cmd` key. None when neither is
+    /// set, see `editor_command` for what's used then.
+    pub fn editor_cmd(&self, cmd: Option<&str>) -> Option<String> {
+        cmd.map(String::from).or_else(|| self.editor_cmd.clone())
+    }
+}
+
+/// `name` in one of tika's directories as the platform lays them out, e.g. under
+/// `$XDG_CONFIG_HOME/tika` or `~/.local/share/tika` on Linux and `~/Library/Application Support`
+/// on macOS. Without a home directory to resolve those from, the tilde-expanded `fallback`.
+fn project_path(dir: impl Fn(&ProjectDirs) -> &Path, name: &str, fallback: &str) -> String {
+    match ProjectDirs::from("cloud", "little-fluffy", "tika") {
+        Some(dirs) => dir(&dirs).join(name).to_string_lossy().into_owned(),
+        None => shellexpand::tilde(fallback).into_owned(),
+    }
+}
+
+/// `tika.toml` in the platform config directory, where the config is read from unless `-c` or
+/// `TIKA_CONFIG` says otherwise
+pub fn default_config_file() -> String {
+    project_path(ProjectDirs::config_dir, "tika.toml", DEFAULT_CONFIG_FILE)
+}
+
+/// `db` in the platform data directory, where the database lives unless `--db` or the `db-path`
+/// key says otherwise
+pub fn default_db_path() -> String {
+    project_path(ProjectDirs::data_dir, "db", DEFAULT_DB_PATH)
+}
+
+/// `history` in the platform data directory, where queries submitted in the TUI are remembered
+pub fn default_history_path() -> String {
+    project_path(ProjectDirs::data_dir, "history", DEFAULT_HISTORY_PATH)
+}
+
+/// Write `DEFAULT_CONFIG` to `cfg_file`, creating its directory if needed. An existing file is
+/// only overwritten with `force`.
+pub fn init_config(cfg_file: &str, force: bool) -> Result<(), Report> {
+    let path = Path::new(cfg_file);
+    if path.exists() && !force {
+        return Err(eyre!(
+            "Config file {} already exists, pass --force to overwrite it",
+            cfg_file
+        ));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create directory {}", dir.display()))?;
+    }
+    fs::write(path, DEFAULT_CONFIG)
+        .wrap_err_with(|| format!("Failed to write config file {}", cfg_file))
+}
+
+/// Markdown files matching the `-s` globs (comma-separated), or the `source-glob` key in the
+/// config. Paths matched by several globs are only returned once. Paths matching any of the
+/// `exclude` patterns or the `exclude-glob` list in the config are skipped, exclusions win over
+/// the source glob. Exclusions are matched against the full path. With `since`, files last
+/// modified before then are skipped too.
+pub fn glob_files(
+    cfg: &Config,
     source: Option<&str>,
-    verbosity: i8,
-) -> Result<Paths, Box<dyn std::error::Error>> {
-    let cfg_fh = fs::OpenOptions::new()
-        .read(true)
-        .write(false)
-        .create(false)
-        .open(cfg_file)?;
-    let mut buf_reader = io::BufReader::new(cfg_fh);
-    let mut contents = String::new();
-    buf_reader.read_to_string(&mut contents)?;
-    let toml_contents = contents.parse::<tomlVal>().unwrap();
+    exclude: &[&str],
+    since: Option<SystemTime>,
+) -> Result<impl Iterator<Item = GlobResult>, Report> {
+    let sources: Vec<&str> = match source {
+        Some(source) => source.split(',').map(str::trim).collect(),
+        None => cfg.source_globs()?,
+    };
+
+    let mut paths = Vec::new();
+    for source in sources {
+        let glob_path = Path::new(&source);
+        let glob_str = shellexpand::tilde(glob_path.to_str().unwrap());
+
+        info!("Sourcing Markdown documents matching: {}", glob_str);
+        paths
+            .push(glob(&glob_str).wrap_err_with(|| format!("Invalid source glob '{}'", glob_str))?);
+    }
+
+    let excludes = cfg
+        .exclude_globs
+        .iter()
+        .map(String::as_str)
+        .chain(exclude.iter().copied())
+        .map(|p| {
+            Pattern::new(&shellexpand::tilde(p))
+                .wrap_err_with(|| format!("Invalid exclude glob '{}'", p))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for p in &excludes {
+        info!("Excluding Markdown documents matching: {}", p);
+    }
+
+    let cwd = env::current_dir()?;
+    let mut seen = HashSet::new();
+    Ok(paths
+        .into_iter()
+        .flatten()
+        .filter(move |entry| match entry {
+            Ok(path) => {
+                let full_path = cwd.join(path);
+                !excludes.iter().any(|p| p.matches_path(&full_path))
+                    && since.map_or(true, |since| modified_since(path, since))
+                    && seen.insert(full_path)
+            }
+            // Leave errors for the caller to report
+            Err(_) => true,
+        }))
+}
+
+/// Whether `path` was modified at or after `since`. Files whose mtime can't be read are kept, so
+/// indexing reports the problem rather than silently skipping them.
+fn modified_since(path: &Path, since: SystemTime) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_or(true, |mtime| mtime >= since)
+}
+
+/// Parse the `--since` argument, either an RFC 3339 timestamp or a duration back from now such as
+/// `90s`, `30m`, `12h`, `7d` or `2w`
+pub fn parse_since(since: &str) -> Result<SystemTime, Report> {
+    let since = since.trim();
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(since) {
+        return Ok(SystemTime::from(t));
+    }
+
+    let invalid = || {
+        eyre!(
+            "Invalid --since '{}', expected an RFC 3339 timestamp or a duration such as 30m, 12h or 7d",
+            since
+        )
+    };
+    let split = since
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let count: u64 = since[..split].parse().map_err(|_| invalid())?;
+    let unit = match &since[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let secs = count.checked_mul(unit).ok_or_else(invalid)?;
+    SystemTime::now()
+        .checked_sub(Duration::from_secs(secs))
+        .ok_or_else(invalid)
+}
+
+/// Previously submitted queries, oldest first. A missing history file is just an empty history.
+pub fn load_history(path: &str) -> io::Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(String::from).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write the most recent `HISTORY_LIMIT` queries to the history file, creating its directory if
+/// needed
+pub fn save_history(path: &str, history: &[String]) -> io::Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let start = history.len().saturating_sub(HISTORY_LIMIT);
+    let mut contents = history[start..].join("\n");
+    contents.push('\n');
+    fs::write(path, contents)
+}
 
-    let source_glob = toml_contents
-        .get("source-glob")
-        .expect("Failed to find 'source-glob' heading in toml config")
-        .as_str()
-        .expect("Error taking source-glob value as string");
+/// Split a command line into words the way a shell would, honouring single and double quotes and
+/// backslash escapes, e.g. `code --goto "{path}"`
+fn split_command(cmd: &str) -> Result<Vec<String>, Report> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\\' => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| eyre!("Trailing backslash in editor command '{}'", cmd))?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            '\'' | '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' => word.extend(chars.next()),
+                        Some(other) => word.push(other),
+                        None => {
+                            return Err(eyre!("Unterminated quote in editor command '{}'", cmd))
+                        }
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
 
-    let source = source.unwrap_or(source_glob);
-    let glob_path = Path::new(&source);
-    let glob_str = shellexpand::tilde(glob_path.to_str().unwrap());
+/// The command line to open `paths` with, from the `template` set by `editor_cmd`. Every word
+/// containing `{path}` is repeated for each path with it substituted, the paths are appended when
+/// there's no `{path}`. Without a template `$VISUAL` or `$EDITOR` is used, falling back to `vi`.
+/// Paths needn't be UTF-8, they're passed on as they are.
+pub fn editor_command(template: Option<&str>, paths: &[OsString]) -> Result<Vec<OsString>, Report> {
+    let template = match template {
+        Some(template) => template.to_string(),
+        None => env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .unwrap_or_else(|_| String::from("vi")),
+    };
+    let words = split_command(&template)?;
+    if words.is_empty() {
+        return Err(eyre!("Empty editor command"));
+    }
+
+    let mut command = Vec::new();
+    let mut substituted = false;
+    for word in words {
+        if word.contains("{path}") {
+            substituted = true;
+            command.extend(paths.iter().map(|p| replace_path(&word, p)));
+        } else {
+            command.push(OsString::from(word));
+        }
+    }
+    if !substituted {
+        command.extend(paths.iter().cloned());
+    }
+    Ok(command)
+}
 
-    if verbosity > 0 {
-        println!("Sourcing Markdown documents matching : {}", glob_str);
+/// `word` with every `{path}` replaced by `path`
+fn replace_path(word: &str, path: &OsStr) -> OsString {
+    let mut replaced = OsString::new();
+    for (i, part) in word.split("{path}").enumerate() {
+        if i > 0 {
+            replaced.push(path);
+        }
+        replaced.push(part);
     }
+    replaced
+}
 
-    return Ok(glob(&glob_str).expect("Failed to read glob pattern"));
+/// Replace the current process with the editor opened on `paths`, see `editor_command` for how
+/// the command is built from `template`. It never returns Ok, only an error if the editor couldn't
+/// be launched. Call this once the TUI has been torn down, exec'ing rather than spawning also stops
+/// the TUI input thread from competing with the editor for stdin.
+pub fn open_in_editor(paths: &[OsString], template: Option<&str>) -> Result<Infallible, Report> {
+    let command = editor_command(template, paths)?;
+    let err = Command::new(&command[0]).args(&command[1..]).exec();
+    Err(eyre!(
+        "Failed to launch editor '{}': {}",
+        command[0].to_string_lossy(),
+        err
+    ))
 }
 
 pub(crate) mod event {
 
     use std::io;
-    use std::sync::mpsc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
     use std::thread;
     use std::time::Duration;
 
+    use log::debug;
     use termion::event::Key;
     use termion::input::TermRead;
 
@@ -50,11 +492,13 @@ pub(crate) mod event {
     }
 
     /// A small event handler that wrap termion input and tick events. Each event
-    /// type is handled in its own thread and returned to a common `Receiver`
+    /// type is handled in its own thread and returned to a common `Receiver`. Ticks can be paused
+    /// with `set_ticking` while there's nothing to wait on, input keeps coming regardless.
     pub struct Events {
         rx: mpsc::Receiver<Event<Key>>,
         input_handle: thread::JoinHandle<()>,
         tick_handle: thread::JoinHandle<()>,
+        ticking: Arc<AtomicBool>,
     }
 
     #[derive(Debug, Clone, Copy)]
@@ -84,17 +528,24 @@ pub(crate) mod event {
                     for evt in stdin.keys() {
                         if let Ok(key) = evt {
                             if let Err(err) = tx.send(Event::Input(key)) {
-                                eprintln!("{}", err);
+                                debug!("Input thread stopping: {}", err);
                                 return;
                             }
                         }
                     }
                 })
             };
+            let ticking = Arc::new(AtomicBool::new(true));
             let tick_handle = {
+                let ticking = ticking.clone();
                 thread::spawn(move || loop {
+                    // Sleep without waking up at all until `set_ticking` resumes the ticks
+                    if !ticking.load(Ordering::SeqCst) {
+                        thread::park();
+                        continue;
+                    }
                     if let Err(err) = tx.send(Event::Tick) {
-                        eprintln!("{}", err);
+                        debug!("Tick thread stopping: {}", err);
                         break;
                     }
                     thread::sleep(config.tick_rate);
@@ -104,6 +555,15 @@ pub(crate) mod event {
                 rx,
                 input_handle,
                 tick_handle,
+                ticking,
+            }
+        }
+
+        /// Pause or resume the tick events
+        pub fn set_ticking(&self, ticking: bool) {
+            let was_ticking = self.ticking.swap(ticking, Ordering::SeqCst);
+            if ticking && !was_ticking {
+                self.tick_handle.thread().unpark();
             }
         }
 
@@ -112,3 +572,349 @@ pub(crate) mod event {
         }
     }
 }
+
+#[cfg(test)]
+mod query_flags_tests {
+    use super::*;
+    use crate::xapian_utils::{query_flags, DEFAULT_QUERY_FLAGS};
+    use xapian_rusty::FeatureFlag::FlagPartial;
+
+    fn flags_from_config(config: &str) -> Result<i16, Report> {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg_file = dir.path().join("tika.toml");
+        fs::write(&cfg_file, config).expect("Failed to write config");
+        query_flags(&Config::load(cfg_file.to_str().unwrap())?.query_flags())
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(DEFAULT_QUERY_FLAGS, flags_from_config("").unwrap());
+        assert_eq!(
+            DEFAULT_QUERY_FLAGS,
+            query_flags(
+                &Config::load("/nonexistent/tika.toml")
+                    .unwrap()
+                    .query_flags()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn disable_and_enable() {
+        assert_eq!(
+            DEFAULT_QUERY_FLAGS & !(FlagPartial as i16),
+            flags_from_config("[query-flags]\npartial = false\n").unwrap()
+        );
+        // Turning on a default flag changes nothing
+        assert_eq!(
+            DEFAULT_QUERY_FLAGS,
+            flags_from_config("[query-flags]\npartial = true\nboolean = true\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn synonyms() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg_file = dir.path().join("tika.toml");
+        let cfg_file = cfg_file.to_str().unwrap();
+        fs::write(cfg_file, "[synonyms]\nkubernetes = [\"k8s\", \"kube\"]\n")
+            .expect("Failed to write config");
+        assert_eq!(
+            vec![(
+                String::from("kubernetes"),
+                vec![String::from("k8s"), String::from("kube")]
+            )],
+            Config::load(cfg_file).unwrap().synonyms()
+        );
+
+        fs::write(cfg_file, "[synonyms]\nkubernetes = \"k8s\"\n").expect("Failed to write config");
+        assert!(Config::load(cfg_file).is_err());
+    }
+
+    #[test]
+    fn default_operator() {
+        let op = |config: &str| {
+            let dir = tempfile::tempdir().expect("Failed to create tempdir");
+            let cfg_file = dir.path().join("tika.toml");
+            fs::write(&cfg_file, config).expect("Failed to write config");
+            Config::load(cfg_file.to_str().unwrap())?.default_operator()
+        };
+        assert_eq!(DEFAULT_OPERATOR, op("").unwrap());
+        assert_eq!(MatchOp::And, op("default-operator = \"AND\"\n").unwrap());
+        assert_eq!(MatchOp::Or, op("default-operator = \"or\"\n").unwrap());
+        assert!(op("default-operator = \"XOR\"\n").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(flags_from_config("[query-flags]\nfuzzy = true\n").is_err());
+        assert!(flags_from_config("[query-flags]\npartial = \"no\"\n").is_err());
+        assert!(flags_from_config("query-flags = [\"partial\"]\n").is_err());
+    }
+}
+
+#[cfg(test)]
+mod glob_files_tests {
+    use super::*;
+
+    #[test]
+    fn exclusions() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let root = dir.path().to_str().unwrap();
+        for sub in &["notes", "drafts", "templates"] {
+            fs::create_dir(dir.path().join(sub)).expect("Failed to create dir");
+            fs::write(dir.path().join(sub).join("a.md"), "").expect("Failed to write note");
+        }
+        let cfg_file = dir.path().join("tika.toml");
+        fs::write(
+            &cfg_file,
+            format!(
+                "source-glob = \"{}/**/*.md\"\nexclude-glob = [\"{}/templates/*\"]\n",
+                root, root
+            ),
+        )
+        .expect("Failed to write config");
+
+        let drafts = format!("{}/drafts/*", root);
+        let cfg = Config::load(cfg_file.to_str().unwrap()).expect("Failed to load config");
+        let mut found: Vec<String> = glob_files(&cfg, None, &[&drafts], None)
+            .expect("Failed to glob")
+            .map(|p| p.unwrap().strip_prefix(root).unwrap().display().to_string())
+            .collect();
+        found.sort();
+        assert_eq!(vec!["/notes/a.md"], found);
+    }
+
+    #[test]
+    fn multiple_sources() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let root = dir.path().to_str().unwrap();
+        for sub in &["work", "zettel"] {
+            fs::create_dir(dir.path().join(sub)).expect("Failed to create dir");
+            fs::write(dir.path().join(sub).join("a.md"), "").expect("Failed to write note");
+        }
+        let cfg_file = dir.path().join("tika.toml");
+        // The second glob overlaps with the first, shared paths should only be returned once
+        fs::write(
+            &cfg_file,
+            format!(
+                "source-glob = [\"{}/work/*.md\", \"{}/*/*.md\"]\n",
+                root, root
+            ),
+        )
+        .expect("Failed to write config");
+        let cfg = Config::load(cfg_file.to_str().unwrap()).expect("Failed to load config");
+
+        let found = |source: Option<&str>| {
+            let mut found: Vec<String> = glob_files(&cfg, source, &[], None)
+                .expect("Failed to glob")
+                .map(|p| p.unwrap().strip_prefix(root).unwrap().display().to_string())
+                .collect();
+            found.sort();
+            found
+        };
+        assert_eq!(vec!["/work/a.md", "/zettel/a.md"], found(None));
+
+        let source = format!("{}/zettel/*.md, {}/zettel/a.md", root, root);
+        assert_eq!(vec!["/zettel/a.md"], found(Some(&source)));
+    }
+
+    #[test]
+    fn config_errors() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg_file = dir.path().join("tika.toml");
+        let cfg_file = cfg_file.to_str().unwrap();
+
+        let err = |contents: &str| {
+            fs::write(cfg_file, contents).expect("Failed to write config");
+            match Config::load(cfg_file)
+                .and_then(|cfg| glob_files(&cfg, None, &[], None).map(|_| ()))
+            {
+                Ok(_) => panic!("Expected an error for {:?}", contents),
+                Err(e) => format!("{:#}", e),
+            }
+        };
+        assert!(err("db-path = \"/tmp\"\n").contains("No 'source-glob' key"));
+        assert!(err("source-glob = 1\n").contains("source-glob"));
+        assert!(err("source-glob = \"a\" b\n").contains("Failed to parse config file"));
+        assert!(err("source-glob = \"a/***b\"\n").contains("Invalid source glob"));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg_file = dir.path().join("tika.toml");
+        let cfg_file = cfg_file.to_str().unwrap();
+        fs::write(cfg_file, "source_glob = \"~/notes/*.md\"\n").expect("Failed to write config");
+
+        let e = format!(
+            "{:#}",
+            Config::load(cfg_file).expect_err("Loaded a typo'd key")
+        );
+        assert!(e.contains("unknown field `source_glob`"), "{}", e);
+        // The valid keys are listed to pick the right one from
+        assert!(e.contains("`source-glob`"), "{}", e);
+    }
+
+    #[test]
+    fn missing_source_glob() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg_file = dir.path().join("tika.toml");
+        let cfg_file = cfg_file.to_str().unwrap();
+        fs::write(cfg_file, "db-path = \"~/tika-db\"\n").expect("Failed to write config");
+
+        let cfg = Config::load(cfg_file).expect("Failed to load config");
+        let e = glob_files(&cfg, None, &[], None)
+            .map(|_| ())
+            .expect_err("Globbed without a source");
+        assert_eq!(
+            format!(
+                "No 'source-glob' key in config file {} and no -s given",
+                cfg_file
+            ),
+            e.to_string()
+        );
+        // -s stands in for it
+        assert!(glob_files(&cfg, Some("/nonexistent/*.md"), &[], None).is_ok());
+    }
+
+    #[test]
+    fn init() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg_file = dir.path().join("tika").join("tika.toml");
+        let cfg_file = cfg_file.to_str().unwrap();
+
+        init_config(cfg_file, false).expect("Failed to write config");
+        let cfg = Config::load(cfg_file).expect("Failed to load the default config");
+        assert_eq!(vec!["~/notes/**/*.md"], cfg.source_globs().unwrap());
+        assert_eq!(vec!["~/notes/templates/*"], cfg.exclude_globs);
+        assert_eq!("en", cfg.stemmer_language(None));
+
+        // Edits are kept unless forced
+        fs::write(cfg_file, "db-path = \"/tmp/db\"\n").expect("Failed to write config");
+        let e = init_config(cfg_file, false).expect_err("Overwrote the config");
+        assert!(e.to_string().contains("--force"), "{}", e);
+        assert_eq!("/tmp/db", Config::load(cfg_file).unwrap().db_path(None));
+        init_config(cfg_file, true).expect("Failed to overwrite config");
+        assert_eq!(DEFAULT_CONFIG, fs::read_to_string(cfg_file).unwrap());
+    }
+
+    #[test]
+    fn db_path_precedence() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg_file = dir.path().join("tika.toml");
+        let cfg_file = cfg_file.to_str().unwrap();
+
+        let cfg = Config::load(cfg_file).expect("Failed to load config");
+        assert_eq!(default_db_path(), cfg.db_path(None));
+
+        fs::write(cfg_file, "db-path = \"/tmp/tika-db\"\n").expect("Failed to write config");
+        let cfg = Config::load(cfg_file).expect("Failed to load config");
+        assert_eq!("/tmp/tika-db", cfg.db_path(None));
+        assert_eq!("/srv/db", cfg.db_path(Some("/srv/db")));
+    }
+
+    #[test]
+    fn since_filter() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let root = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("a.md"), "").expect("Failed to write note");
+        let cfg_file = dir.path().join("tika.toml");
+        fs::write(&cfg_file, format!("source-glob = \"{}/*.md\"\n", root))
+            .expect("Failed to write config");
+        let cfg = Config::load(cfg_file.to_str().unwrap()).expect("Failed to load config");
+
+        let found = |since: &str| {
+            let since = parse_since(since).expect("Failed to parse --since");
+            glob_files(&cfg, None, &[], Some(since))
+                .expect("Failed to glob")
+                .count()
+        };
+        assert_eq!(1, found("1h"));
+        assert_eq!(1, found("2021-06-22T12:48:16-04:00"));
+        assert_eq!(0, found("2999-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn since_durations() {
+        let ago = |since: &str| {
+            let t = parse_since(since).expect("Failed to parse --since");
+            SystemTime::now().duration_since(t).unwrap().as_secs()
+        };
+        assert!((7200..7260).contains(&ago("2h")));
+        assert!((7 * 86400..7 * 86400 + 60).contains(&ago("1w")));
+        assert!(parse_since("2 days").is_err());
+        assert!(parse_since("h").is_err());
+        assert!(parse_since("yesterday").is_err());
+        // Too long ago to represent
+        assert!(parse_since("3000000000000000000w").is_err());
+        assert!(parse_since("18446744073709551615s").is_err());
+    }
+
+    #[test]
+    fn editor_templates() {
+        let paths = vec![
+            OsString::from("/notes/a b.md"),
+            OsString::from("/notes/c.md"),
+        ];
+        let command = |template| editor_command(Some(template), &paths).unwrap();
+
+        assert_eq!(
+            vec!["vi", "/notes/a b.md", "/notes/c.md"],
+            command("vi {path}")
+        );
+        // Without a placeholder the paths go on the end
+        assert_eq!(
+            vec!["code", "--wait", "/notes/a b.md", "/notes/c.md"],
+            command("code --wait")
+        );
+        assert_eq!(
+            vec![
+                "tmux",
+                "new-window",
+                "nvim -c 'set spell'",
+                "--file=/notes/a b.md",
+                "--file=/notes/c.md"
+            ],
+            command(r#"tmux new-window "nvim -c 'set spell'" --file={path}"#)
+        );
+        assert_eq!(
+            vec!["my editor", "/notes/c.md"],
+            editor_command(Some(r"my\ editor"), &paths[1..]).unwrap()
+        );
+
+        assert!(editor_command(Some("vi 'unterminated {path}"), &paths).is_err());
+        assert!(editor_command(Some("  "), &paths).is_err());
+    }
+
+    #[test]
+    fn editor_non_utf8_path() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let paths = vec![OsString::from_vec(b"/notes/caf\xe9.md".to_vec())];
+        assert_eq!(
+            vec![
+                OsString::from("vi"),
+                OsString::from_vec(b"--file=/notes/caf\xe9.md".to_vec())
+            ],
+            editor_command(Some("vi --file={path}"), &paths).unwrap()
+        );
+        assert_eq!(
+            vec![OsString::from("vi"), paths[0].clone()],
+            editor_command(Some("vi"), &paths).unwrap()
+        );
+    }
+
+    #[test]
+    fn history_round_trip() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = dir.path().join("nested").join("history");
+        let path = path.to_str().unwrap();
+
+        assert!(load_history(path).expect("Failed to load").is_empty());
+        let history = vec![String::from("tag:rust"), String::from("author:steve")];
+        save_history(path, &history).expect("Failed to save");
+        assert_eq!(history, load_history(path).expect("Failed to load"));
+    }
+}
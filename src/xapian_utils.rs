@@ -1,24 +1,129 @@
 use crate::tika_document::TikaDocument;
 use color_eyre::Report;
-use eyre::{eyre, Result};
+use eyre::{eyre, Result, WrapErr};
+use log::{debug, trace};
 #[allow(unused)]
 use nom::{
+    bytes::complete::{
+        is_not as complete_is_not, tag as complete_tag, tag_no_case as complete_tag_no_case,
+    },
     bytes::streaming::{is_not, tag, tag_no_case, take_until},
     character::complete::multispace1 as complete_multispace1,
+    character::complete::{char, digit1 as complete_digit1, multispace0 as complete_multispace0},
     character::streaming::{alphanumeric0, alphanumeric1, multispace0, multispace1, space0},
-    combinator::{recognize, value},
-    multi::{many0, many1},
-    sequence::{delimited, pair, separated_pair, tuple},
+    combinator::{complete, cut, map, recognize, value, verify},
+    error::{Error as NomError, ErrorKind},
+    multi::{fold_many0, many0, many1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    InputTake,
     {alt, branch::alt, complete, delimited, named, tag, take_until, value}, // {IResult},
 };
+use serde::Serialize;
+use std::collections::HashSet;
 use std::convert::From;
 use std::fmt;
+use std::path::Path;
 use std::str;
 use xapian_rusty::FeatureFlag::{
-    FlagBoolean, FlagBooleanAnyCase, FlagLovehate, FlagPartial, FlagPhrase, FlagPureNot,
-    FlagSpellingCorrection, FlagWildcard,
+    FlagAutoSynonyms, FlagBoolean, FlagBooleanAnyCase, FlagLovehate, FlagPartial, FlagPhrase,
+    FlagPureNot, FlagSpellingCorrection, FlagWildcard,
 };
-use xapian_rusty::{Database, Query, QueryParser, Stem, XapianOp, DB_CREATE_OR_OVERWRITE};
+use xapian_rusty::{Database, Enquire, Query, QueryParser, Stem, XapianOp, DB_OPEN};
+
+/// Value slot holding the document date as a sortable `YYYYMMDD` string
+pub const DATE_SLOT: u32 = 0;
+/// Value slot holding the file modification time as a zero-padded, sortable string of seconds
+pub const MTIME_SLOT: u32 = 1;
+
+/// Database metadata key recording the stemmer language the index was built with
+pub const STEMMER_LANGUAGE_KEY: &str = "tika:stemmer-language";
+
+/// Languages, by name or ISO 639 code, Xapian ships a Snowball stemmer for
+pub const STEMMER_LANGUAGES: &[&str] = &[
+    "none",
+    "ar",
+    "arabic",
+    "hy",
+    "armenian",
+    "eu",
+    "basque",
+    "ca",
+    "catalan",
+    "da",
+    "danish",
+    "nl",
+    "dutch",
+    "en",
+    "english",
+    "fi",
+    "finnish",
+    "fr",
+    "french",
+    "de",
+    "german",
+    "hu",
+    "hungarian",
+    "id",
+    "indonesian",
+    "ga",
+    "irish",
+    "it",
+    "italian",
+    "lt",
+    "lithuanian",
+    "ne",
+    "nepali",
+    "nb",
+    "nn",
+    "no",
+    "norwegian",
+    "pt",
+    "portuguese",
+    "ro",
+    "romanian",
+    "ru",
+    "russian",
+    "es",
+    "spanish",
+    "sv",
+    "swedish",
+    "ta",
+    "tamil",
+    "tr",
+    "turkish",
+];
+
+/// Construct the stemmer for `lang`, rejecting languages Xapian doesn't support
+pub fn stemmer(lang: &str) -> Result<Stem, Report> {
+    if !STEMMER_LANGUAGES.contains(&lang.to_lowercase().as_str()) {
+        return Err(eyre!(
+            "Unsupported stemmer language '{}', expected one of: {}",
+            lang,
+            STEMMER_LANGUAGES.join(", ")
+        ));
+    }
+    Ok(Stem::new(&lang.to_lowercase())?)
+}
+
+/// How to order query matches
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    Relevance,
+    /// Most recently modified first
+    Mtime,
+}
+
+impl str::FromStr for SortOrder {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relevance" => Ok(SortOrder::Relevance),
+            "mtime" => Ok(SortOrder::Mtime),
+            _ => Err(eyre!("Unknown sort order '{}'", s)),
+        }
+    }
+}
 
 // The most helpful write-up on using Nom that I've seen so far:
 //   https://iximiuz.com/en/posts/rust-writing-parsers-with-nom/
@@ -42,6 +147,19 @@ pub enum MatchOp {
     Synonym,
 }
 
+impl MatchOp {
+    /// How tightly the operator binds when building a query tree, higher binds tighter. Follows
+    /// standard boolean precedence where AND-like operators bind tighter than OR and XOR. NEAR and
+    /// PHRASE join single words, so they bind tightest.
+    pub fn precedence(self) -> u8 {
+        match self {
+            MatchOp::Or | MatchOp::Xor => 1,
+            MatchOp::Near | MatchOp::Phrase => 3,
+            _ => 2,
+        }
+    }
+}
+
 // Support converting into and from XapianOps
 impl From<MatchOp> for XapianOp {
     fn from(item: MatchOp) -> Self {
@@ -107,22 +225,55 @@ impl fmt::Display for MatchOp {
 }
 
 pub fn matchop(input: &str) -> IResult<MatchOp> {
+    operator(Span::new(input))
+}
+
+/// Match the operator keyword `kw` only as a whole word, it has to be followed by whitespace, a
+/// parenthesis or the end of input. Otherwise words such as "android" would match AND.
+fn keyword<'a>(kw: &'static str) -> impl Fn(Span<'a>) -> IResult<'a, Span<'a>> {
+    move |input: Span<'a>| {
+        let (rest, matched) = tag_no_case(kw)(input)?;
+        match rest.fragment().chars().next() {
+            Some(c) if !(c.is_whitespace() || c == '(' || c == ')') => {
+                Err(nom::Err::Error(NomError::new(input, ErrorKind::Tag)))
+            }
+            _ => Ok((rest, matched)),
+        }
+    }
+}
+
+/// The operators that can join two expressions. Xapian's others, such as SYNONYM or VALUE_RANGE,
+/// don't take subqueries the way these do, so words like "range" stay plain search terms.
+fn operator(input: Span) -> IResult<MatchOp> {
     alt((
-        value(MatchOp::AndMaybe, tag_no_case("AND MAYBE")),
-        value(MatchOp::Synonym, tag_no_case("SYNONYM")),
-        value(MatchOp::AndNot, tag_no_case("AND NOT")),
-        value(MatchOp::Filter, tag_no_case("FILTER")),
-        value(MatchOp::Phrase, tag_no_case("PHRASE")),
-        value(MatchOp::ScaleWeight, tag_no_case("SCALED")),
-        value(MatchOp::ValueRange, tag_no_case("RANGE")),
-        value(MatchOp::EliteSet, tag_no_case("ELITE")),
-        value(MatchOp::Near, tag_no_case("NEAR")),
-        value(MatchOp::And, tag_no_case("AND")),
-        value(MatchOp::Xor, tag_no_case("XOR")),
-        value(MatchOp::Or, tag_no_case("OR")),
-        value(MatchOp::ValueGe, tag_no_case(">")),
-        value(MatchOp::ValueLe, tag_no_case("<")),
-    ))(Span::new(input))
+        value(MatchOp::AndMaybe, keyword("AND MAYBE")),
+        value(MatchOp::AndNot, keyword("AND NOT")),
+        value(MatchOp::Filter, keyword("FILTER")),
+        value(MatchOp::Phrase, keyword("PHRASE")),
+        value(MatchOp::Near, keyword("NEAR")),
+        value(MatchOp::And, keyword("AND")),
+        value(MatchOp::Xor, keyword("XOR")),
+        value(MatchOp::Or, keyword("OR")),
+    ))(input)
+}
+
+/// NEAR or PHRASE with the distance the words may be apart, e.g. `NEAR/5`
+fn proximity_operator(input: Span) -> IResult<(MatchOp, u32)> {
+    let (rest, (op, distance)) = separated_pair(
+        alt((
+            value(MatchOp::Near, complete_tag_no_case("NEAR")),
+            value(MatchOp::Phrase, complete_tag_no_case("PHRASE")),
+        )),
+        complete_tag("/"),
+        complete_digit1,
+    )(input)?;
+    match (rest.fragment().chars().next(), distance.fragment().parse()) {
+        (Some(c), _) if !(c.is_whitespace() || c == '(' || c == ')') => {
+            Err(nom::Err::Error(NomError::new(input, ErrorKind::Digit)))
+        }
+        (_, Ok(distance)) => Ok((rest, (op, distance))),
+        (_, Err(_)) => Err(nom::Err::Error(NomError::new(input, ErrorKind::Digit))),
+    }
 }
 
 #[cfg(test)]
@@ -132,6 +283,34 @@ mod matchop_tests {
     fn test1() {
         let (rest, op) = matchop("AND\n").expect("Failed to match Op");
     }
+
+    #[test]
+    fn whole_words_only() {
+        assert_eq!(
+            MatchOp::And,
+            matchop("and foo").expect("Failed to match Op").1
+        );
+        assert_eq!(
+            MatchOp::Or,
+            matchop("OR(foo)").expect("Failed to match Op").1
+        );
+        assert_eq!(
+            MatchOp::AndNot,
+            matchop("AND NOT foo").expect("Failed to match Op").1
+        );
+        // AND followed by a word that merely starts with NOT
+        assert_eq!(
+            MatchOp::And,
+            matchop("and notable").expect("Failed to match Op").1
+        );
+        for word in &["android ", "order ", "oregano ", "xoring "] {
+            assert!(matchop(word).is_err(), "{} matched an operator", word);
+        }
+        // Only the operators that join subqueries
+        for word in &["SYNONYM ", "SCALED ", "RANGE ", "ELITE ", "> ", "< "] {
+            assert!(matchop(word).is_err(), "{} matched an operator", word);
+        }
+    }
 }
 
 use nom_locate::LocatedSpan;
@@ -189,12 +368,12 @@ impl ExpectedParseResult<'_> {
         assert_eq!(&self.matched_fragment, matched.fragment());
         assert_eq!(self.matched_offset, matched.location_offset());
         assert_eq!(self.matched_line, matched.location_line());
-        assert_eq!(self.matched_column, matched.get_column());
+        assert_eq!(self.matched_column, matched.get_utf8_column());
 
         assert_eq!(&self.rest_fragment, rest.fragment());
         assert_eq!(self.rest_offset, rest.location_offset());
         assert_eq!(self.rest_line, rest.location_line());
-        assert_eq!(self.rest_column, rest.get_column());
+        assert_eq!(self.rest_column, rest.get_utf8_column());
     }
 }
 
@@ -222,6 +401,7 @@ mod word_tests {
     }
 }
 
+#[allow(dead_code)]
 fn words(input: Span) -> IResult<Span> {
     recognize(many1(alt((recognize(multispace1), recognize(word)))))(input)
 }
@@ -246,17 +426,42 @@ mod words_tests {
     }
 }
 
+/// A run of anything but whitespace, e.g. `e-mail`, `c++` or an unterminated `"quote`. Xapian's
+/// own parser makes what it can of the punctuation, the same as it would for a plain query.
+fn literal(input: Span) -> IResult<Span> {
+    complete_is_not(" \t\r\n")(input)
+}
+
+#[cfg(test)]
+mod literal_tests {
+    use super::*;
+    #[test]
+    fn punctuation() {
+        ExpectedParseResult::new(&"e-mail", 0, 1, 1, &" setup", 6, 1, 7)
+            .compare(&literal, &r#"e-mail setup"#)
+    }
+
+    #[test]
+    fn unterminated_quote() {
+        ExpectedParseResult::new(&"\"bar", 0, 1, 1, &" baz", 4, 1, 5)
+            .compare(&literal, &r#""bar baz"#)
+    }
+}
+
+/// A phrase in double or single quotes. Anything but the closing quote may be quoted, so a field
+/// value such as `author:"O'Brien, Pat"` stays a single token. An unterminated quote is an
+/// error rather than incomplete input, so the rest of the expression still gets tokenized.
 fn quoted(input: Span) -> IResult<Span> {
     recognize(alt((
         delimited(
-            recognize(tag(r#"""#)),
-            recognize(words),
-            recognize(tag(r#"""#)),
+            complete_tag(r#"""#),
+            complete_is_not(r#"""#),
+            complete_tag(r#"""#),
         ),
         delimited(
-            recognize(tag(r#"'"#)),
-            recognize(words),
-            recognize(tag(r#"'"#)),
+            complete_tag(r#"'"#),
+            complete_is_not(r#"'"#),
+            complete_tag(r#"'"#),
         ),
     )))(input)
 }
@@ -296,6 +501,20 @@ mod quoted_tests {
             .compare(&quoted, &r#"'foo bar'"#)
     }
 
+    #[test]
+    fn unterminated() {
+        assert!(matches!(
+            quoted(Span::new(r#""foo bar ;"#)),
+            Err(nom::Err::Error(_))
+        ));
+    }
+
+    #[test]
+    fn punctuation() {
+        ExpectedParseResult::new(&"\"O'Brien, Pat\"", 0, 1, 1, &" ", 14, 1, 15)
+            .compare(&quoted, &r#""O'Brien, Pat" "#)
+    }
+
     #[test]
     fn tag_entirely_single_quoted() {
         // The colon character currently isn't an allowed `word` character
@@ -367,6 +586,63 @@ pub enum XapianTag {
     Title,
     Subtitle,
     Tag,
+    /// Wikilink targets
+    Links,
+}
+
+/// Prefix of the boolean terms holding each whole tag, neither stemmed nor split into words.
+/// `tag:` queries match these, so `tag:Go` finds notes tagged Go rather than ones about going.
+pub const EXACT_TAG_PREFIX: &str = "XK";
+
+/// The boolean term for `tag`, see `EXACT_TAG_PREFIX`. Lowercased both when indexing and querying,
+/// so `tag:rust` still finds a note tagged Rust.
+pub fn exact_tag_term(tag: &str) -> String {
+    format!("{}{}", EXACT_TAG_PREFIX, tag.to_lowercase())
+}
+
+/// Prefix for the custom front matter field `key`, e.g. `XMPROJECT` for `project:`. The `XM` keeps
+/// custom fields clear of the built in prefixes.
+pub fn custom_field_prefix(key: &str) -> String {
+    format!("XM{}", key.to_uppercase())
+}
+
+/// A query on a custom front matter field, e.g. `project:tika`
+fn custom_field(input: Span) -> IResult<(Span, Span)> {
+    separated_pair(word, tag(":"), alt((quoted, word)))(input)
+}
+
+/// Prefix of the boolean terms recording which custom front matter fields a note has, e.g.
+/// `XFproject`. Queries only take `key:value` as a field when some note has the key, so text such
+/// as `10:30` or `John 3:16` stays plain words.
+pub const CUSTOM_FIELD_KEY_PREFIX: &str = "XF";
+
+/// The boolean term recording that a note has the custom field `key`, see
+/// `CUSTOM_FIELD_KEY_PREFIX`
+pub fn custom_field_key_term(key: &str) -> String {
+    format!("{}{}", CUSTOM_FIELD_KEY_PREFIX, key.to_lowercase())
+}
+
+/// The lowercased keys of every custom front matter field indexed in `db`
+pub fn indexed_custom_fields(db: &mut Database) -> Result<HashSet<String>, Report> {
+    Ok(terms_with_prefix(db, CUSTOM_FIELD_KEY_PREFIX)?
+        .into_iter()
+        .map(|f| f.value)
+        .collect())
+}
+
+/// The key and value `token` queries, if it's a custom field in `custom_fields`. The key has to
+/// start with a letter.
+fn known_custom_field<'a>(
+    token: Span<'a>,
+    custom_fields: &HashSet<String>,
+) -> Option<(Span<'a>, Span<'a>)> {
+    let (_rest, (key, value)) = custom_field(token).ok()?;
+    let key_str = key.fragment();
+    if key_str.starts_with(char::is_alphabetic) && custom_fields.contains(&key_str.to_lowercase()) {
+        Some((key, value))
+    } else {
+        None
+    }
 }
 
 impl XapianTag {
@@ -379,6 +655,7 @@ impl XapianTag {
             XapianTag::Title => "S",
             XapianTag::Subtitle => "XS",
             XapianTag::Tag => "K",
+            XapianTag::Links => "XL",
         }
     }
     pub fn parse(input: Span) -> IResult<(XapianTag, Span)> {
@@ -391,6 +668,7 @@ impl XapianTag {
                 value(XapianTag::Title, tag_no_case("title")),
                 value(XapianTag::Date, tag_no_case("date")),
                 value(XapianTag::Tag, tag_no_case("tag")),
+                value(XapianTag::Links, tag_no_case("links")),
             )),
             tag(":"),
             alt((quoted, word)),
@@ -398,6 +676,24 @@ impl XapianTag {
     }
 }
 
+impl str::FromStr for XapianTag {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "author" => Ok(XapianTag::Author),
+            "date" => Ok(XapianTag::Date),
+            "filename" => Ok(XapianTag::Filename),
+            "fullpath" => Ok(XapianTag::Fullpath),
+            "title" => Ok(XapianTag::Title),
+            "subtitle" => Ok(XapianTag::Subtitle),
+            "tag" => Ok(XapianTag::Tag),
+            "links" => Ok(XapianTag::Links),
+            _ => Err(eyre!("Unknown field '{}'", s)),
+        }
+    }
+}
+
 impl fmt::Display for XapianTag {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "<{}>", self.to_xapian())
@@ -436,51 +732,274 @@ mod xapiantag_tests {
     }
 }
 
+// A date in a range query, e.g. `2021-06-22` or `20210622`
+fn date(input: Span) -> IResult<Span> {
+    recognize(many1(alt((complete_digit1, complete_tag("-")))))(input)
+}
+
+// Normalize a date from a range query into the `YYYYMMDD` form stored in `DATE_SLOT`
+fn sortable_date(date: Span) -> Result<String, Report> {
+    let digits: String = date
+        .fragment()
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect();
+    if digits.len() != 8 {
+        return Err(eyre!(
+            "Invalid date '{}' at column {}, expected YYYY-MM-DD",
+            date.fragment(),
+            date.get_utf8_column()
+        ));
+    }
+    Ok(digits)
+}
+
+// The first and last day a full or partial date covers, in the `YYYYMMDD` form stored in
+// `DATE_SLOT`, e.g. all of June 2021 for `2021-06` or `202106`
+fn date_bounds(date: Span) -> Result<(String, String), Report> {
+    let digits: String = date
+        .fragment()
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect();
+    match digits.len() {
+        4 | 6 | 8 => Ok((format!("{:0<8}", digits), format!("{:9<8}", digits))),
+        _ => Err(eyre!(
+            "Invalid date '{}' at column {}, expected YYYY, YYYY-MM or YYYY-MM-DD",
+            date.fragment(),
+            date.get_utf8_column()
+        )),
+    }
+}
+
+/// A single full or partial date, e.g. `date:2021-06-22` or `date:202106`
+fn date_tag(input: Span) -> IResult<Span> {
+    preceded(complete_tag_no_case("date:"), date)(input)
+}
+
+/// Date range, e.g. `date:2021-01-01..2021-12-31`, both ends are inclusive
+fn date_range(input: Span) -> IResult<(Span, Span)> {
+    preceded(
+        complete_tag_no_case("date:"),
+        separated_pair(date, complete_tag(".."), date),
+    )(input)
+}
+
+#[cfg(test)]
+mod date_range_tests {
+    use super::*;
+    #[test]
+    fn dashed_dates() {
+        let (rest, (start, end)) =
+            date_range(Span::new("date:2021-01-01..2021-12-31")).expect("Failed to parse");
+        assert_eq!(&"", rest.fragment());
+        assert_eq!("20210101", sortable_date(start).unwrap());
+        assert_eq!("20211231", sortable_date(end).unwrap());
+    }
+
+    #[test]
+    fn compact_dates() {
+        let (_rest, (start, end)) =
+            date_range(Span::new("DATE:20210101..20211231 ")).expect("Failed to parse");
+        assert_eq!(&"20210101", start.fragment());
+        assert_eq!(&"20211231", end.fragment());
+    }
+
+    #[test]
+    fn not_a_range() {
+        assert!(date_range(Span::new("date:2021-01-01")).is_err());
+        assert!(sortable_date(Span::new("2021-01")).is_err());
+    }
+
+    #[test]
+    fn range_query() {
+        let mut query = parse_user_query("date:2021-01-01..2021-12-31").expect("Failed to parse");
+        assert_eq!(
+            "Query(VALUE_RANGE 0 20210101 20211231)",
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn full_date_query() {
+        for qstr in &["date:2021-06-22", "date:20210622"] {
+            let mut query = parse_user_query(qstr).expect("Failed to parse");
+            assert_eq!(
+                "Query(VALUE_RANGE 0 20210622 20210622)",
+                query.get_description()
+            );
+        }
+        // The rest of the run is still there
+        let mut query = parse_user_query("date:2021-06-22 tag:x").expect("Failed to parse");
+        assert_eq!(
+            "Query((VALUE_RANGE 0 20210622 20210622 AND XKx))",
+            query.get_description()
+        );
+        assert!(parse_user_query("date:2021-06-2").is_err());
+    }
+
+    #[test]
+    fn partial_date_query() {
+        let mut query = parse_user_query("date:202106").expect("Failed to parse");
+        assert_eq!(
+            "Query(VALUE_RANGE 0 20210600 20210699)",
+            query.get_description()
+        );
+        let mut query = parse_user_query("date:2021").expect("Failed to parse");
+        assert_eq!(
+            "Query(VALUE_RANGE 0 20210000 20219999)",
+            query.get_description()
+        );
+        let mut query = parse_user_query("date:2021-06").expect("Failed to parse");
+        assert_eq!(
+            "Query(VALUE_RANGE 0 20210600 20210699)",
+            query.get_description()
+        );
+    }
+}
+
 fn expression(input: Span) -> IResult<Vec<Span>> {
-    many1(alt((quoted, tagged, word, multispace1)))(input)
+    many1(alt((
+        quoted,
+        recognize(date_range),
+        recognize(date_tag),
+        tagged,
+        literal,
+        multispace1,
+    )))(input)
 }
 
 fn whitespace(input: Span) -> IResult<Span> {
     recognize(many1(complete_multispace1))(input)
 }
 
-fn span_into_query(qp: &mut QueryParser, flags: i16, token: Span) -> Result<Query, Report> {
+fn span_into_query(
+    qp: &mut QueryParser,
+    flags: i16,
+    custom_fields: &HashSet<String>,
+    token: Span,
+) -> Result<Query, Report> {
+    if let Ok((_rest, (start, end))) = date_range(token) {
+        return Ok(Query::new_range(
+            MatchOp::ValueRange.into(),
+            DATE_SLOT,
+            &sortable_date(start)?,
+            &sortable_date(end)?,
+        )?);
+    }
+
+    if let Ok((_rest, date)) = date_tag(token) {
+        // Match every day the date covers, e.g. all of June 2021 for `date:2021-06`
+        let (start, end) = date_bounds(date)?;
+        return Ok(Query::new_range(
+            MatchOp::ValueRange.into(),
+            DATE_SLOT,
+            &start,
+            &end,
+        )?);
+    }
+
     match XapianTag::parse(token) {
+        Ok((_rest, (XapianTag::Tag, value))) => {
+            let tag = value.fragment().trim_matches(|c| c == '"' || c == '\'');
+            Ok(Query::new_term(&exact_tag_term(tag))?)
+        }
         Ok((_rest, (tag, value))) => {
-            //println!("TAG: {} {} {}", tag.to_xapian(), value, _rest);
+            trace!("Field {:?} ({}) value '{}'", tag, tag.to_xapian(), value);
             Ok(qp.parse_query_with_prefix(&value, flags, tag.to_xapian())?)
         }
-        Err(_e) => {
-            //println!("Span: {} Error: {}", token, e);
-            Ok(qp.parse_query(*token, flags)?)
-        }
+        Err(_e) => match known_custom_field(token, custom_fields) {
+            Some((key, value)) => Ok(qp.parse_query_with_prefix(
+                &value,
+                flags,
+                &custom_field_prefix(key.fragment()),
+            )?),
+            None => {
+                trace!("Plain terms '{}'", token.fragment());
+                Ok(qp.parse_query(*token, flags)?)
+            }
+        },
     }
 }
 
-fn expression_into_query(mut qp: QueryParser, flags: i16, qstr: &str) -> Result<Query, Report> {
-    // Parse the query string into a Vec of matches
-    let mut matches = match expression(Span::new(qstr)) {
-        Ok((_rest, matches)) => matches.into_iter(),
-        Err(_) => return Ok(qp.parse_query("", flags)?),
-    };
+/// The prefix of the field `token` searches, e.g. `K` for `tag:rust`, or None for a plain word or
+/// quoted phrase
+fn token_field(token: Span, custom_fields: &HashSet<String>) -> Option<String> {
+    if date_range(token).is_ok() || date_tag(token).is_ok() {
+        return Some(XapianTag::Date.to_xapian().to_string());
+    }
+    match XapianTag::parse(token) {
+        Ok((_rest, (tag, _value))) => Some(tag.to_xapian().to_string()),
+        Err(_e) => known_custom_field(token, custom_fields)
+            .map(|(key, _value)| custom_field_prefix(key.fragment())),
+    }
+}
 
-    let token = matches.next();
-    if token.is_none() {
-        return Err(eyre!("Empty expression"));
+/// Whether `query` is Xapian's empty query, which is what whitespace or punctuation on its own
+/// parses to. Joined by AND it would match nothing at all.
+fn is_empty_query(query: &mut Query) -> bool {
+    query.get_description() == "Query()"
+}
+
+/// `queries` joined left to right by `op`, None if there aren't any
+fn join_queries(queries: Vec<Query>, op: MatchOp) -> Result<Option<Query>, Report> {
+    let mut joined: Option<Query> = None;
+    for mut query in queries {
+        joined = Some(match joined {
+            Some(joined) => joined.add_right(op.into(), &mut query)?,
+            None => query,
+        });
     }
+    Ok(joined)
+}
 
-    let mut query = span_into_query(&mut qp, flags, token.unwrap())?;
+/// Query for a run of terms. The plain words and quoted phrases are joined by `default_op`, AND
+/// or OR. Field terms narrow those matches down: they're ORed with terms of the same field, and
+/// ANDed with the other fields and the plain words, so with OR `tag:rust async runtime` is
+/// `XKrust AND (async OR runtime)`. Only the keys in `custom_fields` are custom fields, see
+/// `indexed_custom_fields`.
+fn expression_into_query(
+    qp: &mut QueryParser,
+    flags: i16,
+    default_op: MatchOp,
+    custom_fields: &HashSet<String>,
+    qstr: &str,
+) -> Result<Query, Report> {
+    // Parse the query string into a Vec of matches
+    let tokens = match expression(Span::new(qstr)) {
+        Ok((rest, tokens)) if rest.fragment().is_empty() => tokens,
+        _ if qstr.trim().is_empty() => return Ok(qp.parse_query("", flags)?),
+        // Any text at all is a token, so only input without the trailing ` ;` gets here
+        _ => return Err(eyre!("Failed to parse '{}'", qstr)),
+    };
 
-    for token in matches {
-        // Skip whitespace-only tokens
-        if let Ok(_) = whitespace(token) {
+    let mut words = Vec::new();
+    // In the order the fields first appear
+    let mut fields: Vec<(String, Vec<Query>)> = Vec::new();
+    for token in tokens {
+        let mut query = span_into_query(qp, flags, custom_fields, token)?;
+        if is_empty_query(&mut query) {
             continue;
         }
-
-        query = query.add_right(XapianOp::OpOr, &mut span_into_query(&mut qp, flags, token)?)?;
+        match token_field(token, custom_fields) {
+            None => words.push(query),
+            Some(field) => match fields.iter_mut().find(|(f, _)| *f == field) {
+                Some((_, queries)) => queries.push(query),
+                None => fields.push((field, vec![query])),
+            },
+        }
     }
 
-    Ok(query)
+    let mut groups = Vec::new();
+    groups.extend(join_queries(words, default_op)?);
+    for (_field, queries) in fields {
+        groups.extend(join_queries(queries, MatchOp::Or)?);
+    }
+    // Nothing but punctuation, which matches nothing
+    match join_queries(groups, MatchOp::And)? {
+        Some(query) => Ok(query),
+        None => Ok(qp.parse_query("", flags)?),
+    }
 }
 
 #[cfg(test)]
@@ -488,233 +1007,1255 @@ mod expression_tests {
     use super::*;
     #[test]
     fn example1() {
-        let mut qp = QueryParser::new().expect("Failed to create queryparser");
-        let mut stem = Stem::new("en").expect("Failed to create stemmer");
-        qp.set_stemmer(&mut stem).expect("Failed to set stemmer");
-
-        let flags = FlagBoolean as i16
-            | FlagPhrase as i16
-            | FlagLovehate as i16
-            | FlagBooleanAnyCase as i16
-            | FlagWildcard as i16
-            | FlagPureNot as i16
-            | FlagPartial as i16
-            | FlagSpellingCorrection as i16;
+        let (mut qp, flags) = make_query_parser("en").expect("Failed to create queryparser");
 
+        // The trailing backslash is punctuation, leaving the word `n`
         let s = &r#"title:foo  baz bar author:bob hee tag:rust "hee hee hee" \n"#;
-        let mut query = expression_into_query(qp, flags, s).expect("Failed to parse");
-        assert_eq!("Query((((((((WILDCARD SYNONYM Sfoo OR ZSfoo@1) OR (WILDCARD SYNONYM baz OR Zbaz@1)) OR (WILDCARD SYNONYM bar OR Zbar@1)) OR (WILDCARD SYNONYM Abob OR ZAbob@1)) OR (WILDCARD SYNONYM hee OR Zhee@1)) OR (WILDCARD SYNONYM Krust OR ZKrust@1)) OR (hee@1 PHRASE 3 hee@2 PHRASE 3 hee@3)))",
+        let mut query = expression_into_query(&mut qp, flags, DEFAULT_OPERATOR, &HashSet::new(), s)
+            .expect("Failed to parse");
+        assert_eq!("Query(((((((((WILDCARD SYNONYM baz OR Zbaz@1) OR (WILDCARD SYNONYM bar OR Zbar@1)) OR (WILDCARD SYNONYM hee OR Zhee@1)) OR (hee@1 PHRASE 3 hee@2 PHRASE 3 hee@3)) OR (WILDCARD SYNONYM n OR Zn@1)) AND (WILDCARD SYNONYM Sfoo OR ZSfoo@1)) AND (WILDCARD SYNONYM Abob OR ZAbob@1)) AND XKrust))",
         query.get_description(),
         "Generated query didn't match expected for input string '{}'", s);
     }
 
     #[test]
     fn example2() {
-        let mut qp = QueryParser::new().expect("Failed to create queryparser");
-        let mut stem = Stem::new("en").expect("Failed to create stemmer");
-        qp.set_stemmer(&mut stem).expect("Failed to set stemmer");
-
-        let flags = FlagBoolean as i16
-            | FlagPhrase as i16
-            | FlagLovehate as i16
-            | FlagBooleanAnyCase as i16
-            | FlagWildcard as i16
-            | FlagPureNot as i16
-            | FlagPartial as i16
-            | FlagSpellingCorrection as i16;
+        let (mut qp, flags) = make_query_parser("en").expect("Failed to create queryparser");
 
         let s = &r#"title:"foo bar" author:bob tag:rust\n"#;
-        let mut query = expression_into_query(qp, flags, s).expect("Failed to parse");
-        assert_eq!("Query((((Sfoo@1 PHRASE 2 Sbar@2) OR (WILDCARD SYNONYM Abob OR ZAbob@1)) OR (tag@1 PHRASE 2 rust@2)))",
+        let mut query = expression_into_query(&mut qp, flags, DEFAULT_OPERATOR, &HashSet::new(), s)
+            .expect("Failed to parse");
+        assert_eq!("Query(((((tag@1 PHRASE 2 rust@2) OR (WILDCARD SYNONYM n OR Zn@1)) AND (Sfoo@1 PHRASE 2 Sbar@2)) AND (WILDCARD SYNONYM Abob OR ZAbob@1)))",
         query.get_description(),
         "Generated query didn't match expected for input string '{}'", s);
     }
 
     #[test]
     fn example3() {
-        let mut qp = QueryParser::new().expect("Failed to create queryparser");
-        let mut stem = Stem::new("en").expect("Failed to create stemmer");
-        qp.set_stemmer(&mut stem).expect("Failed to set stemmer");
-
-        let flags = FlagBoolean as i16
-            | FlagPhrase as i16
-            | FlagLovehate as i16
-            | FlagBooleanAnyCase as i16
-            | FlagWildcard as i16
-            | FlagPureNot as i16
-            | FlagPartial as i16
-            | FlagSpellingCorrection as i16;
+        let (mut qp, flags) = make_query_parser("en").expect("Failed to create queryparser");
 
         let s = &r#"title:foo "baz bar" author:"bob alice" hee tag:rust "hee hee"\n"#;
-        let mut query = expression_into_query(qp, flags, s).expect("Failed to parse");
-        assert_eq!("Query(((((((WILDCARD SYNONYM Sfoo OR ZSfoo@1) OR (baz@1 PHRASE 2 bar@2)) OR (Abob@1 PHRASE 2 Aalice@2)) OR (WILDCARD SYNONYM hee OR Zhee@1)) OR (WILDCARD SYNONYM Krust OR ZKrust@1)) OR (hee@1 PHRASE 2 hee@2)))",
+        let mut query = expression_into_query(&mut qp, flags, DEFAULT_OPERATOR, &HashSet::new(), s)
+            .expect("Failed to parse");
+        assert_eq!("Query((((((((baz@1 PHRASE 2 bar@2) OR (WILDCARD SYNONYM hee OR Zhee@1)) OR (hee@1 PHRASE 2 hee@2)) OR (WILDCARD SYNONYM n OR Zn@1)) AND (WILDCARD SYNONYM Sfoo OR ZSfoo@1)) AND (Abob@1 PHRASE 2 Aalice@2)) AND XKrust))",
         query.get_description(),
         "Generated query didn't match expected for input string '{}'", s);
     }
 }
 
+/// Intermediate representation of a user query: runs of search terms joined by operators, with
+/// parenthesized groups nested as subtrees
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryExpr<'a> {
+    Terms(Span<'a>),
+    Op(MatchOp, Box<QueryExpr<'a>>, Box<QueryExpr<'a>>),
+    /// Words at most the given distance apart, in order for PHRASE, e.g. `foo NEAR/5 bar`
+    Near(MatchOp, u32, Box<QueryExpr<'a>>, Box<QueryExpr<'a>>),
+    /// Every document not matching the expression, e.g. `NOT tag:archived`
+    Not(Box<QueryExpr<'a>>),
+}
+
+/// How far apart the words joined by NEAR or PHRASE may be without an explicit distance, the same
+/// as Xapian's default
+const DEFAULT_PROXIMITY: u32 = 10;
+
+impl fmt::Display for QueryExpr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryExpr::Terms(terms) => write!(f, "[{}]", terms.fragment().trim()),
+            QueryExpr::Op(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op, rhs),
+            QueryExpr::Near(op, distance, lhs, rhs) => {
+                write!(f, "({} {}/{} {})", lhs, op, distance, rhs)
+            }
+            QueryExpr::Not(expr) => write!(f, "(<Not> {})", expr),
+        }
+    }
+}
+
+impl QueryExpr<'_> {
+    /// Each run of terms split the way `expression` tokenizes it, into field tags, quoted phrases,
+    /// date ranges and words, along with the column of the query each token starts at
+    pub fn tokens(&self) -> Vec<(usize, String)> {
+        match self {
+            QueryExpr::Terms(terms) => {
+                // The same trailing ` ;` as `into_query`, so the streaming parsers see the end
+                let input = format!("{} ;", terms.fragment());
+                match expression(Span::new(&input)) {
+                    Ok((_rest, tokens)) => tokens
+                        .into_iter()
+                        .filter(|token| {
+                            whitespace(*token).is_err()
+                                && token.location_offset() < terms.fragment().len()
+                        })
+                        .map(|token| {
+                            (
+                                terms.get_utf8_column() + token.get_utf8_column() - 1,
+                                token.fragment().trim_end().to_string(),
+                            )
+                        })
+                        .collect(),
+                    Err(_) => Vec::new(),
+                }
+            }
+            QueryExpr::Op(_, lhs, rhs) | QueryExpr::Near(_, _, lhs, rhs) => {
+                let mut tokens = lhs.tokens();
+                tokens.extend(rhs.tokens());
+                tokens
+            }
+            QueryExpr::Not(expr) => expr.tokens(),
+        }
+    }
+
+    fn into_query(
+        self,
+        qp: &mut QueryParser,
+        flags: i16,
+        default_op: MatchOp,
+        custom_fields: &HashSet<String>,
+    ) -> Result<Query, Report> {
+        match self {
+            // Each run of terms may contain field tags such as `author:` or `tag:`, so parse it as
+            // an expression. Add a trailing ` ;` to hint to Nom that it has a "full" string
+            QueryExpr::Terms(terms) => expression_into_query(
+                qp,
+                flags,
+                default_op,
+                custom_fields,
+                &format!("{} ;", terms.fragment()),
+            ),
+            QueryExpr::Op(op, lhs, rhs) => {
+                let mut rhs = rhs.into_query(qp, flags, default_op, custom_fields)?;
+                Ok(lhs
+                    .into_query(qp, flags, default_op, custom_fields)?
+                    .add_right(op.into(), &mut rhs)?)
+            }
+            // Xapian's own parser positions the words, with its `NEAR/n` and `ADJ/n` syntax. The
+            // last word can't be matched partially, a wildcard has no position.
+            near @ QueryExpr::Near(..) => Ok(qp.parse_query(
+                &near.proximity_query_string()?,
+                flags & !(FlagPartial as i16),
+            )?),
+            QueryExpr::Not(expr) => {
+                let mut negated = expr.into_query(qp, flags, default_op, custom_fields)?;
+                Ok(match_all_query()?.add_right(MatchOp::AndNot.into(), &mut negated)?)
+            }
+        }
+    }
+
+    /// This expression in Xapian's query syntax, if it's a single word or words joined by NEAR
+    /// or PHRASE
+    fn proximity_query_string(&self) -> Result<String, Report> {
+        match self {
+            QueryExpr::Terms(terms)
+                if !terms.fragment().trim().is_empty()
+                    && terms
+                        .fragment()
+                        .trim()
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || c == '_') =>
+            {
+                Ok(terms.fragment().trim().to_string())
+            }
+            QueryExpr::Near(op, distance, lhs, rhs) => Ok(format!(
+                "{} {}/{} {}",
+                lhs.proximity_query_string()?,
+                if *op == MatchOp::Phrase {
+                    "ADJ"
+                } else {
+                    "NEAR"
+                },
+                distance,
+                rhs.proximity_query_string()?
+            )),
+            QueryExpr::Terms(terms) => Err(eyre!(
+                "NEAR and PHRASE only join single words, not '{}' at column {}",
+                terms.fragment().trim(),
+                terms.get_utf8_column()
+            )),
+            QueryExpr::Op(..) | QueryExpr::Not(_) => {
+                Err(eyre!("NEAR and PHRASE only join single words"))
+            }
+        }
+    }
+}
+
+/// Operators can only start at the beginning of a word, e.g. don't split `author:` at the `or`
+fn at_word_start(frag: &str, i: usize) -> bool {
+    frag[..i]
+        .chars()
+        .next_back()
+        .map_or(true, |c| c.is_whitespace() || c == '(' || c == ')')
+}
+
+/// Whether a quote at byte offset `i` of `frag` opens a quoted phrase, rather than being an
+/// apostrophe within a word, e.g. `"foo bar"` or `title:'foo bar'` but not `don't`
+fn opens_quote(frag: &str, i: usize) -> bool {
+    at_word_start(frag, i) || frag[..i].ends_with(':')
+}
+
+/// Take search terms up to the next operator or parenthesis. Operators and parentheses within a
+/// quoted phrase are taken literally, as part of the phrase.
+fn terms(input: Span) -> IResult<Span> {
+    let frag = *input.fragment();
+    let mut quote = None;
+    let end = frag
+        .char_indices()
+        .find(|&(i, c)| match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+                false
+            }
+            None if (c == '"' || c == '\'') && opens_quote(frag, i) => {
+                quote = Some(c);
+                false
+            }
+            None => {
+                c == '('
+                    || c == ')'
+                    || (at_word_start(frag, i)
+                        && (matchop(&frag[i..]).is_ok()
+                            || proximity_operator(Span::new(&frag[i..])).is_ok()))
+            }
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(frag.len());
+
+    if frag[..end].trim().is_empty() {
+        return Err(nom::Err::Error(NomError::new(input, ErrorKind::TakeUntil)));
+    }
+
+    Ok(input.take_split(end))
+}
+
+/// A parenthesized expression. Once the `(` is seen there's no backtracking, so a missing `)` is
+/// reported where it was expected rather than at whatever came before the group.
+fn group(input: Span) -> IResult<QueryExpr> {
+    preceded(
+        pair(char('('), complete_multispace0),
+        cut(terminated(
+            query_expr,
+            pair(complete_multispace0, char(')')),
+        )),
+    )(input)
+}
+
+/// A group or run of terms, negated by a leading NOT. A NOT with nothing after it to negate is
+/// taken as a word.
+fn operand(input: Span) -> IResult<QueryExpr> {
+    alt((
+        map(
+            preceded(
+                pair(complete(keyword("NOT")), complete_multispace0),
+                operand,
+            ),
+            |expr| QueryExpr::Not(Box::new(expr)),
+        ),
+        group,
+        map(terms, QueryExpr::Terms),
+    ))(input)
+}
+
+/// Fold `next` expressions joined by operators of the given precedence, left to right. An operator
+/// has to be followed by an operand, there's no backtracking past it.
+fn fold_operators<'a>(
+    input: Span<'a>,
+    precedence: u8,
+    next: fn(Span<'a>) -> IResult<'a, QueryExpr<'a>>,
+) -> IResult<'a, QueryExpr<'a>> {
+    let (rest, first) = next(input)?;
+    fold_many0(
+        pair(
+            delimited(
+                complete_multispace0,
+                verify(
+                    alt((
+                        map(complete(proximity_operator), |(op, d)| (op, Some(d))),
+                        map(complete(operator), |op| (op, None)),
+                    )),
+                    |(op, _): &(MatchOp, Option<u32>)| op.precedence() == precedence,
+                ),
+                complete_multispace0,
+            ),
+            cut(next),
+        ),
+        first,
+        |lhs, ((op, distance), rhs)| match op {
+            MatchOp::Near | MatchOp::Phrase => QueryExpr::Near(
+                op,
+                distance.unwrap_or(DEFAULT_PROXIMITY),
+                Box::new(lhs),
+                Box::new(rhs),
+            ),
+            _ => QueryExpr::Op(op, Box::new(lhs), Box::new(rhs)),
+        },
+    )(rest)
+}
+
+/// Parse a full query expression. OR and XOR bind loosest, so they join AND-level expressions,
+/// which in turn join NEAR-level ones; parenthesized groups are parsed recursively so they bind as
+/// a single operand.
+fn query_expr(input: Span) -> IResult<QueryExpr> {
+    fold_operators(input, 1, and_expr)
+}
+
+fn and_expr(input: Span) -> IResult<QueryExpr> {
+    fold_operators(input, 2, near_expr)
+}
+
+fn near_expr(input: Span) -> IResult<QueryExpr> {
+    fold_operators(input, 3, operand)
+}
+
+#[cfg(test)]
+mod query_expr_tests {
+    use super::*;
+
+    // Description of a phrase parsed with FlagPhrase, phrase terms aren't stemmed
+    const THIS_AND_THAT: &str = "(this@1 PHRASE 3 and@2 PHRASE 3 that@3)";
+
+    // Description of a single word parsed with FlagPartial
+    fn partial(term: &str) -> String {
+        format!("(WILDCARD SYNONYM {} OR Z{}@1)", term, term)
+    }
+
+    #[test]
+    fn grouped_right() {
+        let mut query = parse_user_query("foo AND (bar OR baz)").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query(({} AND ({} OR {})))",
+                partial("foo"),
+                partial("bar"),
+                partial("baz")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn grouped_left() {
+        let mut query = parse_user_query("(foo AND bar) OR baz").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query((({} AND {}) OR {}))",
+                partial("foo"),
+                partial("bar"),
+                partial("baz")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn nested_groups() {
+        let (rest, expr) =
+            query_expr(Span::new("((foo OR bar) AND baz)")).expect("Failed to parse");
+        assert_eq!(&"", rest.fragment());
+        match expr {
+            QueryExpr::Op(MatchOp::And, lhs, _) => {
+                assert!(matches!(*lhs, QueryExpr::Op(MatchOp::Or, _, _)))
+            }
+            _ => panic!("Expected a top level AND, got {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let mut query = parse_user_query("foo OR bar AND baz").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query(({} OR ({} AND {})))",
+                partial("foo"),
+                partial("bar"),
+                partial("baz")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn and_not_binds_tighter_than_xor() {
+        let mut query = parse_user_query("foo AND NOT bar XOR baz").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query((({} AND_NOT {}) XOR {}))",
+                partial("foo"),
+                partial("bar"),
+                partial("baz")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn and_maybe() {
+        // Documents have to match rust, those also matching async rank higher
+        let mut query = parse_user_query("rust AND MAYBE async").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query(({} AND_MAYBE {}))",
+                partial("rust"),
+                partial("async")
+            ),
+            query.get_description()
+        );
+
+        let mut query = parse_user_query("rust and maybe async OR go").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query((({} AND_MAYBE {}) OR {}))",
+                partial("rust"),
+                partial("async"),
+                partial("go")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn filter() {
+        // Operators beyond AND, OR, XOR and AND NOT come from the same grammar, `operator`
+        let mut query = parse_user_query("rust FILTER tag:work").expect("Failed to parse");
+        assert_eq!(
+            format!("Query(({} FILTER XKwork))", partial("rust")),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn long_queries() {
+        // Operators are folded in a loop, there's no cap on how many a query has
+        let words: Vec<String> = (0..60).map(|i| format!("word{}", i)).collect();
+        let mut query = parse_user_query(&words.join(" OR ")).expect("Failed to parse");
+        let description = query.get_description();
+        assert_eq!(59, description.matches(") OR (").count());
+        for word in &words {
+            assert!(description.contains(&partial(word)), "{} missing", word);
+        }
+    }
+
+    #[test]
+    fn blank_matches_all() {
+        for qstr in &["", "  ", "\n"] {
+            let mut query = parse_user_query(qstr).expect("Failed to parse");
+            assert_eq!("Query(<alldocuments>)", query.get_description());
+        }
+    }
+
+    #[test]
+    fn unary_not() {
+        let mut query = parse_user_query("NOT foo").expect("Failed to parse");
+        assert_eq!(
+            format!("Query((<alldocuments> AND_NOT {}))", partial("foo")),
+            query.get_description()
+        );
+
+        // Still the binary operator when it follows AND
+        let mut query = parse_user_query("bar AND NOT foo").expect("Failed to parse");
+        assert_eq!(
+            format!("Query(({} AND_NOT {}))", partial("bar"), partial("foo")),
+            query.get_description()
+        );
+
+        let mut query = parse_user_query("NOT tag:x").expect("Failed to parse");
+        assert_eq!(
+            "Query((<alldocuments> AND_NOT XKx))",
+            query.get_description()
+        );
+
+        let mut query = parse_user_query("bar OR not (foo AND baz)").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query(({} OR (<alldocuments> AND_NOT ({} AND {}))))",
+                partial("bar"),
+                partial("foo"),
+                partial("baz")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn mixed_precedence() {
+        let mut query = parse_user_query("foo AND bar OR baz AND qux").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query((({} AND {}) OR ({} AND {})))",
+                partial("foo"),
+                partial("bar"),
+                partial("baz"),
+                partial("qux")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn group_overrides_precedence() {
+        let mut query = parse_user_query("(foo OR bar) AND baz").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query((({} OR {}) AND {}))",
+                partial("foo"),
+                partial("bar"),
+                partial("baz")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn tags_within_operands() {
+        let mut query = parse_user_query("tag:rust AND author:steve").expect("Failed to parse");
+        assert_eq!(
+            "Query((XKrust AND (WILDCARD SYNONYM Asteve OR ZAsteve@1)))",
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn near() {
+        let mut query = parse_user_query("kubernetes NEAR pod").expect("Failed to parse");
+        assert_eq!(
+            "Query((kubernetes@1 NEAR 11 pod@2))",
+            query.get_description()
+        );
+        let mut query = parse_user_query("kubernetes near/5 pod").expect("Failed to parse");
+        assert_eq!(
+            "Query((kubernetes@1 NEAR 6 pod@2))",
+            query.get_description()
+        );
+        let mut query =
+            parse_user_query("kubernetes NEAR/2 pod NEAR/2 node").expect("Failed to parse");
+        assert_eq!(
+            "Query((kubernetes@1 NEAR 4 pod@2 NEAR 4 node@3))",
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn phrase_distance() {
+        let mut query = parse_user_query("kubernetes PHRASE/2 pod").expect("Failed to parse");
+        assert_eq!(
+            "Query((kubernetes@1 PHRASE 3 pod@2))",
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn near_binds_tightest() {
+        let mut query = parse_user_query("foo AND kubernetes NEAR/5 pod").expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query(({} AND (kubernetes@1 NEAR 6 pod@2)))",
+                partial("foo")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn near_single_words_only() {
+        assert!(parse_user_query("foo bar NEAR baz").is_err());
+        assert!(parse_user_query("(foo OR bar) NEAR baz").is_err());
+        assert!(parse_user_query("tag:rust NEAR baz").is_err());
+        // Only a distance directly after the operator counts
+        assert!(proximity_operator(Span::new("NEAR/5x")).is_err());
+        assert!(proximity_operator(Span::new("NEARBY/5 ")).is_err());
+    }
+
+    #[test]
+    fn custom_fields() {
+        let custom_fields: HashSet<String> = ["project", "status", "10"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        let parse = |qstr: &str, custom_fields: &HashSet<String>| {
+            let (mut qp, flags) = make_query_parser("en").expect("Failed to create queryparser");
+            parse_query_expr(qstr)
+                .expect("Failed to parse")
+                .into_query(&mut qp, flags, DEFAULT_OPERATOR, custom_fields)
+                .expect("Failed to build query")
+                .get_description()
+        };
+
+        assert_eq!(
+            "Query(((WILDCARD SYNONYM XMPROJECTtika OR ZXMPROJECTtika@1) AND (WILDCARD SYNONYM XMSTATUSdone OR ZXMSTATUSdone@1)))",
+            parse("Project:tika AND status:done", &custom_fields)
+        );
+        // Keys that weren't indexed, or don't start with a letter, are plain words
+        for qstr in &["Project:tika", "10:30", "john 3:16"] {
+            let description = parse(qstr, &HashSet::new());
+            assert!(!description.contains("XM"), "{}", description);
+        }
+        let description = parse("10:30", &custom_fields);
+        assert!(!description.contains("XM"), "{}", description);
+    }
+
+    #[test]
+    fn unbalanced_parens() {
+        assert!(parse_user_query("foo AND (bar OR baz").is_err());
+        assert!(parse_user_query("foo AND bar OR baz)").is_err());
+    }
+
+    #[test]
+    fn operator_inside_quotes() {
+        let (rest, taken) =
+            terms(Span::new(r#""this and that" AND foo"#)).expect("Failed to parse");
+        assert_eq!(&r#""this and that" "#, taken.fragment());
+        assert_eq!(&"AND foo", rest.fragment());
+
+        let mut query = parse_user_query(r#""this and that""#).expect("Failed to parse");
+        assert_eq!(format!("Query({})", THIS_AND_THAT), query.get_description());
+
+        let mut query = parse_user_query(r#"'this or that' OR foo"#).expect("Failed to parse");
+        assert_eq!(
+            format!(
+                "Query(({} OR {}))",
+                THIS_AND_THAT.replace(" and@2", " or@2"),
+                partial("foo")
+            ),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn quoted_field_values() {
+        let mut query = parse_user_query(r#"author:"Steve Sosik""#).expect("Failed to parse");
+        assert_eq!(
+            "Query((Asteve@1 PHRASE 2 Asosik@2))",
+            query.get_description()
+        );
+
+        let mut query =
+            parse_user_query(r#"title:'foo bar' AND tag:"to do""#).expect("Failed to parse");
+        assert_eq!(
+            "Query(((Sfoo@1 PHRASE 2 Sbar@2) AND XKto do))",
+            query.get_description()
+        );
+
+        // Punctuation within the quotes is part of the phrase
+        let mut query = parse_user_query(r#"author:"O'Brien, Pat""#).expect("Failed to parse");
+        assert_eq!(
+            "Query((Ao'brien@1 PHRASE 2 Apat@2))",
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn punctuation_is_not_dropped() {
+        // Unterminated quotes, leading or after other words, and words joined by punctuation
+        let cases = [
+            (r#""bar baz"#, ["bar", "baz"]),
+            (r#"foo "bar baz"#, ["bar", "baz"]),
+            ("e-mail setup", ["mail", "setup"]),
+            ("c++ tutorial", ["c++", "tutorial"]),
+        ];
+        for (qstr, words) in &cases {
+            let mut query = parse_user_query(qstr).expect("Failed to parse");
+            let description = query.get_description();
+            for word in words {
+                assert!(
+                    description.contains(word),
+                    "'{}' lost '{}': {}",
+                    qstr,
+                    word,
+                    description
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_operators_are_words() {
+        let expr = parse_query_expr("mountain RANGE map").expect("Failed to parse");
+        assert_eq!("[mountain RANGE map]", expr.to_string());
+
+        let mut query = parse_user_query("mountain RANGE map").expect("Failed to parse");
+        let description = query.get_description();
+        assert!(!description.contains("VALUE_RANGE"), "{}", description);
+        assert!(description.contains("range"), "{}", description);
+    }
+
+    #[test]
+    fn apostrophe_is_not_a_quote() {
+        let (rest, taken) = terms(Span::new("don't AND foo")).expect("Failed to parse");
+        assert_eq!(&"don't ", taken.fragment());
+        assert_eq!(&"AND foo", rest.fragment());
+    }
+
+    #[test]
+    fn embedded_operator_keywords() {
+        for (word, stem) in &[
+            ("understanding", "understand"),
+            ("candor", "candor"),
+            ("android", "android"),
+        ] {
+            let mut query = parse_user_query(word).expect("Failed to parse");
+            assert_eq!(
+                format!("Query((WILDCARD SYNONYM {} OR Z{}@1))", word, stem),
+                query.get_description()
+            );
+        }
+
+        let mut query = parse_user_query("android OR candor").expect("Failed to parse");
+        assert_eq!(
+            format!("Query(({} OR {}))", partial("android"), partial("candor")),
+            query.get_description()
+        );
+    }
+
+    #[test]
+    fn explained() {
+        let expr = parse_query_expr("tag:foo bar AND (baz OR qux)").expect("Failed to parse");
+        assert_eq!("([tag:foo bar] <And> ([baz] <Or> [qux]))", expr.to_string());
+        assert_eq!(
+            vec![
+                (1, "tag:foo".to_string()),
+                (9, "bar".to_string()),
+                (18, "baz".to_string()),
+                (25, "qux".to_string())
+            ],
+            expr.tokens()
+        );
+
+        let e = parse_query_expr("foo AND (bar").expect_err("Parsed an unclosed group");
+        assert!(e.to_string().contains("column 13"), "{}", e);
+
+        // Columns count characters, not bytes
+        let expr = parse_query_expr("café AND thé").expect("Failed to parse");
+        assert_eq!(
+            vec![(1, "café".to_string()), (10, "thé".to_string())],
+            expr.tokens()
+        );
+        let e = parse_user_query("é date:2021-06-2").expect_err("Parsed a partial day");
+        assert!(
+            e.chain().any(|e| e.to_string().contains("column 8")),
+            "{:?}",
+            e
+        );
+    }
+
+    #[test]
+    fn caret_errors() {
+        let e = parse_query_expr("foo AND (bar").expect_err("Parsed an unclosed group");
+        let e = e
+            .downcast_ref::<QuerySyntaxError>()
+            .expect("Not a syntax error");
+        assert_eq!("Expected ')' at column 13", e.summary());
+        assert_eq!(
+            "Expected ')' at column 13\n  foo AND (bar\n              ^",
+            e.to_string()
+        );
+
+        // An operator with nothing after it
+        let e = parse_query_expr("foo AND").expect_err("Parsed a trailing operator");
+        let e = e
+            .downcast_ref::<QuerySyntaxError>()
+            .expect("Not a syntax error");
+        assert_eq!("Expected search terms or a group at column 8", e.summary());
+
+        let e = parse_query_expr("(foo OR bar) AND ()").expect_err("Parsed an empty group");
+        let e = e
+            .downcast_ref::<QuerySyntaxError>()
+            .expect("Not a syntax error");
+        assert_eq!("Expected search terms or a group at column 19", e.summary());
+
+        // Columns count from the start of the input, leading whitespace included
+        let e = parse_query_expr("  OR foo").expect_err("Parsed a leading operator");
+        let e = e
+            .downcast_ref::<QuerySyntaxError>()
+            .expect("Not a syntax error");
+        assert_eq!(3, e.column);
+        assert_eq!("  ^", e.caret());
+    }
+}
+
 #[cfg(test)]
 mod query_tests {
     use super::*;
+
+    // Description of a single word parsed with FlagPartial, matching the word as a prefix or its
+    // stem exactly
+    fn partial(word: &str, stem: &str) -> String {
+        format!("(WILDCARD SYNONYM {} OR Z{}@1)", word, stem)
+    }
+
     #[test]
-    #[ignore] // TODO figure out why this fails
     fn test1() {
-        let query_str = r#"eep op tag:meh fooobarr AND maybe maybe foo AND bar\n"#;
+        let query_str = "eep op tag:meh fooobarr AND maybe maybe foo AND bar\n";
         let mut result = parse_user_query(query_str).expect("Failed to parse");
         assert_eq!(
-            "Query((((Zeep@1 OR Zop@2 OR (tag@3 PHRASE 2 meh@4) OR Zfooobarr@5) AND_MAYBE (Zmayb@1 OR Zfoo@2)) AND (bar@1 PHRASE 2 n@2)))",
-            //"Query(((((eep@1 PHRASE 2 op@2) OR (tag@3 PHRASE 2 meh@4) OR Zfooobarr@5) AND_MAYBE (Zmayb@1 OR Zfoo@2)) AND (bar@1 PHRASE 2 n@2)))",
+            format!(
+                "Query(((((({} OR {}) OR {}) AND XKmeh) AND_MAYBE ({} OR {})) AND {}))",
+                partial("eep", "eep"),
+                partial("op", "op"),
+                partial("fooobarr", "fooobarr"),
+                partial("maybe", "mayb"),
+                partial("foo", "foo"),
+                partial("bar", "bar")
+            ),
             result.get_description()
         );
     }
 
     #[test]
-    #[ignore] // TODO figure out why this fails
     fn test2() {
-        let query_str = r#""eep op" tag:meh fooobarr AND maybe maybe foo AND bar\n"#;
+        let query_str = "\"eep op\" tag:meh fooobarr AND maybe maybe foo AND bar\n";
         let mut result = parse_user_query(query_str).expect("Failed to parse");
         assert_eq!(
-            "Query(((((eep@1 PHRASE 2 op@2) OR (tag@3 PHRASE 2 meh@4) OR Zfooobarr@5) AND_MAYBE (Zmayb@1 OR Zfoo@2)) AND (bar@1 PHRASE 2 n@2)))", 
+            format!(
+                "Query((((((eep@1 PHRASE 2 op@2) OR {}) AND XKmeh) AND_MAYBE ({} OR {})) AND {}))",
+                partial("fooobarr", "fooobarr"),
+                partial("maybe", "mayb"),
+                partial("foo", "foo"),
+                partial("bar", "bar")
+            ),
             result.get_description()
         );
     }
 }
 
-pub fn parse_user_query(mut qstr: &str) -> Result<Query, Report> {
+/// How the words of a query are joined unless the `default-operator` config key says otherwise,
+/// matching notes with any of them
+pub const DEFAULT_OPERATOR: MatchOp = MatchOp::Or;
+
+/// Flags user queries are parsed with unless the `[query-flags]` config table says otherwise
+pub const DEFAULT_QUERY_FLAGS: i16 = FlagBoolean as i16
+    | FlagPhrase as i16
+    | FlagLovehate as i16
+    | FlagBooleanAnyCase as i16
+    | FlagWildcard as i16
+    | FlagPureNot as i16
+    | FlagPartial as i16
+    | FlagSpellingCorrection as i16
+    | FlagAutoSynonyms as i16;
+
+/// The names QueryParser feature flags go by in the `[query-flags]` config table
+const QUERY_FLAG_NAMES: &[(&str, i16)] = &[
+    ("boolean", FlagBoolean as i16),
+    ("phrase", FlagPhrase as i16),
+    ("lovehate", FlagLovehate as i16),
+    ("boolean-any-case", FlagBooleanAnyCase as i16),
+    ("wildcard", FlagWildcard as i16),
+    ("pure-not", FlagPureNot as i16),
+    ("partial", FlagPartial as i16),
+    ("spelling-correction", FlagSpellingCorrection as i16),
+    ("auto-synonyms", FlagAutoSynonyms as i16),
+];
+
+/// `DEFAULT_QUERY_FLAGS` with each named flag in `settings` turned on or off, e.g.
+/// `("partial", false)` to stop the last word of a query matching as a prefix
+pub fn query_flags(settings: &[(String, bool)]) -> Result<i16, Report> {
+    let mut flags = DEFAULT_QUERY_FLAGS;
+    for (name, enabled) in settings {
+        let flag = QUERY_FLAG_NAMES
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, flag)| *flag)
+            .ok_or_else(|| {
+                let names: Vec<&str> = QUERY_FLAG_NAMES.iter().map(|(n, _)| *n).collect();
+                eyre!(
+                    "Unknown query flag '{}', expected one of: {}",
+                    name,
+                    names.join(", ")
+                )
+            })?;
+        if *enabled {
+            flags |= flag;
+        } else {
+            flags &= !flag;
+        }
+    }
+    Ok(flags)
+}
+
+/// Parse `qstr` into a Query, stemming English. Within a run of terms every word, quoted phrase
+/// and field is its own subquery, e.g. `tag:meh` is the exact tag term and `author:bob` is `bob`
+/// under the author prefix. Words and phrases are joined by `DEFAULT_OPERATOR`, fields are ANDed
+/// with them, see `expression_into_query`. Runs are joined by the operators between them: AND, AND NOT, AND
+/// MAYBE and FILTER bind tighter than OR and XOR, all of them left to right, so
+/// `a AND MAYBE b AND c` is `(a AND_MAYBE b) AND c`. Surrounding whitespace, such as a trailing
+/// newline, is ignored, and a blank `qstr` matches every document.
+pub fn parse_user_query(qstr: &str) -> Result<Query, Report> {
+    parse_user_query_with_language(qstr, "en")
+}
+
+/// Parse `qstr` with `DEFAULT_QUERY_FLAGS`, stemming terms for `lang`. This has to match the
+/// language the index was built with, see `indexed_language`.
+pub fn parse_user_query_with_language(qstr: &str, lang: &str) -> Result<Query, Report> {
+    parse_user_query_with_flags(qstr, lang, DEFAULT_QUERY_FLAGS, DEFAULT_OPERATOR, None)
+}
+
+/// A QueryParser stemming for `lang`, along with `DEFAULT_QUERY_FLAGS` to parse with
+pub fn make_query_parser(lang: &str) -> Result<(QueryParser, i16), Report> {
     let mut qp = QueryParser::new()?;
-    let mut stem = Stem::new("en")?;
+    let mut stem = stemmer(lang)?;
     qp.set_stemmer(&mut stem)?;
+    Ok((qp, DEFAULT_QUERY_FLAGS))
+}
+
+/// Parse `qstr` with the QueryParser feature `flags`, stemming terms for `lang` and joining the
+/// words of each run of terms with `default_op`, AND or OR. Words are expanded with the synonyms
+/// stored in `db`, if given, see `indexer::add_synonyms`. Custom fields such as `project:tika`
+/// are only recognized with a `db` that has them indexed, otherwise they're plain words.
+pub fn parse_user_query_with_flags(
+    qstr: &str,
+    lang: &str,
+    flags: i16,
+    default_op: MatchOp,
+    db: Option<&mut Database>,
+) -> Result<Query, Report> {
+    // The configured flags take the place of the defaults
+    let (mut qp, _) = make_query_parser(lang)?;
+    let custom_fields = match db {
+        Some(db) => {
+            qp.set_database(db)?;
+            indexed_custom_fields(db)?
+        }
+        None => HashSet::new(),
+    };
+
+    // Everything, to list every note rather than none
+    if qstr.trim().is_empty() {
+        return match_all_query();
+    }
 
-    let flags = FlagBoolean as i16
-        | FlagPhrase as i16
-        | FlagLovehate as i16
-        | FlagBooleanAnyCase as i16
-        | FlagWildcard as i16
-        | FlagPureNot as i16
-        | FlagPartial as i16
-        | FlagSpellingCorrection as i16;
+    let expr = parse_query_expr(qstr)?;
+    debug!("Parsed query '{}' into {}", qstr.trim(), expr);
 
-    let mut query;
-    let mut operator;
+    expr.into_query(&mut qp, flags, default_op, &custom_fields)
+}
+
+/// A query that couldn't be parsed, with where in it parsing stopped
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuerySyntaxError {
+    pub query: String,
+    /// Column of the offending character, counting characters from 1
+    pub column: usize,
+    pub message: String,
+}
+
+impl QuerySyntaxError {
+    /// The message and column, on one line
+    pub fn summary(&self) -> String {
+        format!("{} at column {}", self.message, self.column)
+    }
+
+    /// A `^` under the offending character, to print below the query
+    pub fn caret(&self) -> String {
+        format!("{}^", " ".repeat(self.column.saturating_sub(1)))
+    }
+}
 
-    // Create the initial query
-    match take_up_to_operator(qstr.as_bytes()) {
-        Ok((rest, matched)) => {
-            query = qp.parse_query(str::from_utf8(matched)?, flags)?;
-            qstr = str::from_utf8(rest)?;
+impl fmt::Display for QuerySyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\n  {}\n  {}",
+            self.summary(),
+            self.query,
+            self.caret()
+        )
+    }
+}
+
+impl std::error::Error for QuerySyntaxError {}
+
+/// Parse `qstr` into the tree of runs of terms and operators a Query gets built from, without
+/// touching Xapian. Parse errors are a `QuerySyntaxError`.
+pub fn parse_query_expr(qstr: &str) -> Result<QueryExpr, Report> {
+    let trimmed = qstr.trim();
+    // Columns count from the start of `qstr`, so the caret lines up with what was typed
+    let indent = qstr[..qstr.len() - qstr.trim_start().len()].chars().count();
+    let error = |at: Span, message: String| QuerySyntaxError {
+        query: qstr.to_string(),
+        column: indent + at.get_utf8_column(),
+        message,
+    };
+
+    match query_expr(Span::new(trimmed)) {
+        Ok((rest, expr)) if rest.fragment().is_empty() => Ok(expr),
+        Ok((rest, _)) => {
+            let unexpected = rest
+                .fragment()
+                .split_whitespace()
+                .next()
+                .unwrap_or_default();
+            Err(error(rest, format!("Unexpected '{}'", unexpected)).into())
+        }
+        // Only the `)` closing a group is matched with `char`
+        Err(nom::Err::Failure(e)) if e.code == ErrorKind::Char => {
+            Err(error(e.input, "Expected ')'".to_string()).into())
         }
-        Err(_) => {
-            // No operator found in the initial string, return a query for the entire string
-            return expression_into_query(qp, flags, qstr);
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+            Err(error(e.input, "Expected search terms or a group".to_string()).into())
         }
+        Err(e) => Err(eyre!("Failed to parse query '{}': {}", qstr, e)),
     }
+}
 
-    // Pop off the operator and store it for the next 'add_right' call
-    if let Ok((rest, op)) = matchop(qstr) {
-        operator = op;
-        qstr = *rest;
-    } else {
-        // This shouldn't ever happen
-        panic!("Couldn't match leading operator in {}", qstr);
-    }
-
-    let mut depth = 0;
-    while qstr.len() > 0 {
-        depth += 1;
-
-        // Take the next chunk up to the next operator and add it to the query
-        match take_up_to_operator(qstr.as_bytes()) {
-            Ok((rest, matched)) => {
-                query = query.add_right(
-                    operator.into(),
-                    &mut qp.parse_query(str::from_utf8(matched)?, flags)?,
-                )?;
-                qstr = str::from_utf8(rest)?;
-            }
-            Err(_e) => {
-                // There are no more operators, parse the rest of the string into a query and break
-                query = query.add_right(
-                    operator.into(),
-                    &mut expression_into_query(qp, flags, qstr)?,
-                )?;
-                // No more operators found, break out of the loop
-                break;
-            }
-        }
+/// Xapian's spelling correction of `qstr`, e.g. "Did you mean: ...", when it differs from `qstr`.
+/// Corrections come from the spelling data `index_files` gathers from the indexed text.
+pub fn spelling_suggestion(
+    db: &mut Database,
+    qstr: &str,
+    lang: &str,
+) -> Result<Option<String>, Report> {
+    let (mut qp, flags) = make_query_parser(lang)?;
+    qp.set_database(db)?;
+
+    // Only the correction is of interest, the query itself comes from `parse_user_query`. Xapian
+    // leaves the rest of the query string as it was, so field prefixes and ranges survive.
+    let qstr = qstr.trim();
+    if qstr.is_empty() || qp.parse_query(qstr, flags).is_err() {
+        return Ok(None);
+    }
+    let corrected = qp.get_corrected_query_string()?;
+    if corrected.is_empty() || corrected == qstr {
+        return Ok(None);
+    }
+    Ok(Some(corrected))
+}
 
-        // Pop off the operator and store it for the next 'add_right' call
-        if let Ok((rest, op)) = matchop(qstr) {
-            operator = op;
-            qstr = *rest;
-        } else {
-            // This shouldn't ever happen
-            panic!("Couldn't match leading operator in {}", qstr);
-        }
-
-        if depth > 50 {
-            panic!("Depth limit reached with remaining '{}'", qstr);
-        }
-    }
-
-    Ok(query)
-}
-
-// TODO is there a better way to handle case insensitity here?
-named!(
-    take_up_to_operator,
-    alt!(
-        complete!(take_until!("AND MAYBE"))
-            | complete!(take_until!("and maybe"))
-            | complete!(take_until!("AND NOT"))
-            | complete!(take_until!("and not"))
-            | complete!(take_until!("SYNONYM"))
-            | complete!(take_until!("synonym"))
-            | complete!(take_until!("FILTER"))
-            | complete!(take_until!("filter"))
-            | complete!(take_until!("PHRASE"))
-            | complete!(take_until!("phrase"))
-            | complete!(take_until!("SCALED"))
-            | complete!(take_until!("scaled"))
-            | complete!(take_until!("ELITE"))
-            | complete!(take_until!("elite"))
-            | complete!(take_until!("RANGE"))
-            | complete!(take_until!("range"))
-            | complete!(take_until!("NEAR"))
-            | complete!(take_until!("near"))
-            | complete!(take_until!("AND"))
-            | complete!(take_until!("and"))
-            | complete!(take_until!("XOR"))
-            | complete!(take_until!("xor"))
-            | complete!(take_until!("OR"))
-            | complete!(take_until!("or"))
-    )
-);
-
-//fn query_db(mut db: Database, mut q: Query) -> Result<Vec<TikaDocument>, Report> {
-pub fn query_db(mut q: Query) -> Result<Vec<TikaDocument>, Report> {
-    // TODO Reuse existing DB instead of creating a new one on each query
-    let mut db = Database::new_with_path("mydb", DB_CREATE_OR_OVERWRITE)?;
+/// Query for the notes with a `[[target]]` wikilink to any of `targets`
+pub fn backlinks_query(targets: &[String]) -> Result<Query, Report> {
+    let mut qp = QueryParser::new()?;
+    let mut query: Option<Query> = None;
+    for target in targets {
+        // Quote the target so multi-word links have to match as a phrase
+        let phrase = format!("\"{}\"", target.replace('"', " "));
+        let mut q =
+            qp.parse_query_with_prefix(&phrase, FlagPhrase as i16, XapianTag::Links.to_xapian())?;
+        query = Some(match query {
+            Some(query) => query.add_right(XapianOp::OpOr, &mut q)?,
+            None => q,
+        });
+    }
+    query.ok_or_else(|| eyre!("No link targets given"))
+}
+
+/// Query matching every document, Xapian's MatchAll is the query for the empty term
+pub fn match_all_query() -> Result<Query, Report> {
+    Ok(Query::new_term("")?)
+}
+
+/// Query for the notes whose filename starts with `fragment`, e.g. `2021-06-22-mee`, for jumping
+/// to a note. The last word is matched as a prefix of the indexed filename words, expanding it
+/// needs the terms in `db`.
+pub fn filename_query(fragment: &str, lang: &str, db: &mut Database) -> Result<Query, Report> {
+    let (mut qp, _) = make_query_parser(lang)?;
+    qp.set_database(db)?;
+    let flags = FlagPartial as i16 | FlagWildcard as i16;
+    Ok(qp.parse_query_with_prefix(fragment.trim(), flags, XapianTag::Filename.to_xapian())?)
+}
+
+/// Query for `qstr` against note titles alone, for finding a note by name. The whole string is
+/// parsed by Xapian with the title prefix, field tags and the operators of `parse_user_query`
+/// don't apply. A blank `qstr` matches every document, as with `parse_user_query`.
+pub fn title_query(
+    qstr: &str,
+    lang: &str,
+    flags: i16,
+    db: Option<&mut Database>,
+) -> Result<Query, Report> {
+    let (mut qp, _) = make_query_parser(lang)?;
+    if let Some(db) = db {
+        qp.set_database(db)?;
+    }
+    if qstr.trim().is_empty() {
+        return match_all_query();
+    }
+    Ok(qp.parse_query_with_prefix(qstr.trim(), flags, XapianTag::Title.to_xapian())?)
+}
+
+/// Open the database at `db_path` for querying. Queries only ever need read access, never create
+/// or overwrite the index from here. A missing index is an error naming the path looked in, rather
+/// than an empty set of matches.
+pub fn open_db(db_path: &str) -> Result<Database, Report> {
+    if !Path::new(db_path).is_dir() {
+        return Err(eyre!(
+            "No index found at {}, build one with `tika -i` or point to it with --db",
+            db_path
+        ));
+    }
+    Database::new_with_path(db_path, DB_OPEN)
+        .wrap_err_with(|| format!("Failed to open the index at {}", db_path))
+}
+
+/// The stemmer language recorded when the index was built, None for indexes built before it was
+/// recorded
+pub fn indexed_language(db: &mut Database) -> Result<Option<String>, Report> {
+    let lang = db.get_metadata(STEMMER_LANGUAGE_KEY)?;
+    Ok(if lang.is_empty() { None } else { Some(lang) })
+}
+
+/// A document matching a query, along with how relevant it was
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct QueryMatch {
+    #[serde(flatten)]
+    pub doc: TikaDocument,
+    /// Relevance as a percentage of the best possible match
+    pub percent: i32,
+    /// 1-based position in the results
+    pub rank: usize,
+}
+
+/// A distinct indexed value of a field
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Facet {
+    pub value: String,
+    /// How many documents have the value
+    pub count: usize,
+}
+
+/// Every distinct term in `db` starting with `prefix`, with the prefix stripped. Sorted by term.
+pub fn terms_with_prefix(db: &mut Database, prefix: &str) -> Result<Vec<Facet>, Report> {
+    let mut facets = Vec::new();
+    let mut t = db.allterms_with_prefix(prefix)?;
+    while t.is_next()? {
+        let term = t.get_term()?;
+        facets.push(Facet {
+            value: term[prefix.len()..].to_string(),
+            count: t.get_termfreq()? as usize,
+        });
+        t.next()?;
+    }
+    Ok(facets)
+}
+
+/// The distinct values indexed for `field`. Tags are listed whole, lowercased, from their exact
+/// terms, so each can be fed back into a `tag:` query. Other fields are indexed as free text, so
+/// these are the individual lowercased words, e.g. an author "Steve Sosik" gives "steve" and
+/// "sosik".
+pub fn facets(db: &mut Database, field: XapianTag) -> Result<Vec<Facet>, Report> {
+    match field {
+        XapianTag::Tag => terms_with_prefix(db, EXACT_TAG_PREFIX),
+        _ => terms_with_prefix(db, field.to_xapian()),
+    }
+}
+
+/// How many matches to fetch per MSet when paging through every match
+const MSET_PAGE_SIZE: usize = 100;
+
+/// One MSet worth of matches, or every match `query_db` was asked for
+#[derive(Debug, Default)]
+pub struct QueryPage {
+    pub matches: Vec<QueryMatch>,
+    /// Xapian's estimate of the total number of matches, across every page
+    pub estimated: usize,
+}
+
+fn new_enquire(db: &mut Database, q: &mut Query, sort: SortOrder) -> Result<Enquire, Report> {
     let mut enq = db.new_enquire()?;
-    enq.set_query(&mut q)?;
-    // TODO set this based on terminal height?
-    let mut mset = enq.get_mset(0, 100)?;
+    enq.set_query(q)?;
+    if sort == SortOrder::Mtime {
+        // Descending, newest first
+        enq.set_sort_by_value(MTIME_SLOT, true)?;
+    }
+    Ok(enq)
+}
 
-    // TODO with verbose logging log this:
-    //let appx_matches = mset.get_matches_estimated()?;
-    //println!("Approximate Matches {}", appx_matches);
+/// Fetch `size` matches starting `offset` matches in, ranked from `offset + 1`
+fn get_page(enq: &mut Enquire, offset: usize, size: usize) -> Result<QueryPage, Report> {
+    let mut mset = enq.get_mset(offset as i32, size as i32)?;
+    let mut page = QueryPage {
+        matches: Vec::new(),
+        estimated: mset.get_matches_estimated()? as usize,
+    };
 
-    let mut matches = Vec::new();
+    let mut rank = offset;
     let mut v = mset.iterator().unwrap();
     while v.is_next().unwrap() {
+        rank += 1;
         let res = v.get_document_data();
         // Can use flatten() or some other iterators/combinators?
         if let Ok(data) = res {
-            let v: TikaDocument = serde_json::from_str(&data)?;
-            //println!("Match {}", v.filename);
-            matches.push(v);
+            let doc: TikaDocument = serde_json::from_str(&data)?;
+            page.matches.push(QueryMatch {
+                doc,
+                percent: v.get_percent()?,
+                rank,
+            });
         }
         v.next()?;
     }
 
-    Ok(matches)
+    Ok(page)
+}
+
+/// Run `q` against `db`, returning the page of at most `size` matches starting `offset` in
+pub fn query_db_page(
+    db: &mut Database,
+    mut q: Query,
+    sort: SortOrder,
+    offset: usize,
+    size: usize,
+) -> Result<QueryPage, Report> {
+    let mut enq = new_enquire(db, &mut q, sort)?;
+    get_page(&mut enq, offset, size)
+}
+
+/// Number of documents matching `q`, without loading any of them. Xapian's estimate unless
+/// `exact`, which has the match run over every document to give an exact count.
+pub fn count_matches(db: &mut Database, mut q: Query, exact: bool) -> Result<usize, Report> {
+    let mut enq = new_enquire(db, &mut q, SortOrder::Relevance)?;
+    // The estimate is exact once the MSet is asked for more documents than could match
+    let size = if exact { db.get_doccount()? as i32 } else { 0 };
+    let mut mset = enq.get_mset(0, size)?;
+    Ok(mset.get_matches_estimated()? as usize)
+}
+
+/// Run `q` against `db`, returning at most `limit` matches, or every match when `limit` is 0,
+/// along with Xapian's estimate of how many there are in all
+pub fn query_db(
+    db: &mut Database,
+    mut q: Query,
+    sort: SortOrder,
+    limit: usize,
+) -> Result<QueryPage, Report> {
+    let mut enq = new_enquire(db, &mut q, sort)?;
+    if limit != 0 {
+        return get_page(&mut enq, 0, limit);
+    }
+
+    // Uncapped, keep paging through the MSet until it runs out
+    let mut matches = Vec::new();
+    let mut offset = 0;
+    loop {
+        let page = get_page(&mut enq, offset, MSET_PAGE_SIZE)?;
+        offset += MSET_PAGE_SIZE;
+        // The estimate isn't necessarily exact, a short page is what signals the end
+        let last = page.matches.len() < MSET_PAGE_SIZE;
+        matches.extend(page.matches);
+        if last {
+            break;
+        }
+    }
+
+    // Every match has been fetched, so the count is exact
+    Ok(QueryPage {
+        estimated: matches.len(),
+        matches,
+    })
+}
+
+#[cfg(test)]
+mod stemmer_tests {
+    use super::*;
+
+    #[test]
+    fn supported_languages() {
+        assert!(stemmer("en").is_ok());
+        assert!(stemmer("German").is_ok());
+        assert!(stemmer("fr").is_ok());
+    }
+
+    #[test]
+    fn unsupported_language() {
+        assert!(stemmer("klingon").is_err());
+        assert!(parse_user_query_with_language("foo", "klingon").is_err());
+    }
 }
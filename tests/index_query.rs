@@ -0,0 +1,189 @@
+//! End to end: write notes to disk, index them into a fresh database and query it
+
+use std::fs;
+use tika::index_path;
+use tika::xapian_utils::{
+    open_db, parse_user_query, parse_user_query_with_flags, query_db, title_query, MatchOp,
+    SortOrder, DEFAULT_QUERY_FLAGS,
+};
+
+/// A tempdir holding the notes and the database indexed from them
+struct Fixture {
+    dir: tempfile::TempDir,
+}
+
+impl Fixture {
+    fn new(notes: &[(&str, &str)]) -> Fixture {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        for (name, contents) in notes {
+            fs::write(dir.path().join(name), contents).expect("Failed to write note");
+        }
+        let fixture = Fixture { dir };
+        let glob = format!("{}/*.md", fixture.dir.path().display());
+        let indexed = index_path(&fixture.db_path(), &glob).expect("Failed to index");
+        assert_eq!(notes.len(), indexed);
+        fixture
+    }
+
+    fn db_path(&self) -> String {
+        self.dir.path().join("db").to_str().unwrap().to_string()
+    }
+
+    /// Filenames of the matches for `qstr`, sorted so assertions don't depend on relevance
+    fn query(&self, qstr: &str) -> Vec<String> {
+        let mut db = open_db(&self.db_path()).expect("Failed to open database");
+        let query = parse_user_query(qstr).expect("Failed to parse");
+        let mut found: Vec<String> = query_db(&mut db, query, SortOrder::Relevance, 0)
+            .expect("Failed to query")
+            .matches
+            .into_iter()
+            .map(|m| m.doc.filename)
+            .collect();
+        found.sort();
+        found
+    }
+}
+
+fn notes() -> Fixture {
+    Fixture::new(&[
+        (
+            "lighthouses.md",
+            "---\nauthor: Steve Sosik\ndate: 2021-06-22\ntags:\n- travel\n- history\ntitle: Lighthouses\n---\nThe keeper trimmed the lamp every night.\n",
+        ),
+        (
+            "rust.md",
+            "+++\nauthor = \"Steve Sosik\"\ndate = \"2021-07-01\"\ntags = [\"rust\", \"programming\"]\ntitle = \"Rust notes\"\n+++\nOwnership and borrowing keep the lamp of memory safety lit.\n",
+        ),
+        (
+            "garden.md",
+            "---\nauthor: Someone Else\ndate: 2021-08-15\ntags: garden\ntitle: Garden\n---\nTomatoes need trimming every week.\n",
+        ),
+    ])
+}
+
+#[test]
+fn plain_term() {
+    let notes = notes();
+    assert_eq!(vec!["lighthouses.md", "rust.md"], notes.query("lamp"));
+    // Stemmed, so "trimming" matches "trimmed" too
+    assert_eq!(vec!["garden.md", "lighthouses.md"], notes.query("trim"));
+    assert!(notes.query("submarine").is_empty());
+}
+
+#[test]
+fn match_all() {
+    let notes = notes();
+    let all = vec!["garden.md", "lighthouses.md", "rust.md"];
+    assert_eq!(all, notes.query(""));
+    assert_eq!(all, notes.query(" \n"));
+}
+
+#[test]
+fn tags() {
+    let notes = notes();
+    assert_eq!(vec!["rust.md"], notes.query("tag:rust"));
+    assert_eq!(vec!["lighthouses.md"], notes.query("tag:travel"));
+    assert!(notes.query("tag:lamp").is_empty());
+}
+
+#[test]
+fn fields_with_free_text() {
+    let notes = notes();
+    // Both notes mention a lamp, only one is tagged rust
+    assert_eq!(vec!["rust.md"], notes.query("tag:rust lamp"));
+    assert_eq!(vec!["lighthouses.md"], notes.query("lamp tag:travel"));
+    // The words are still ORed with each other
+    assert_eq!(vec!["rust.md"], notes.query("tag:rust keeper lamp"));
+    assert!(notes.query("tag:rust tomatoes").is_empty());
+    // Terms of the same field are ORed, different fields ANDed
+    assert_eq!(
+        vec!["garden.md", "rust.md"],
+        notes.query("tag:garden tag:rust")
+    );
+    assert_eq!(vec!["rust.md"], notes.query("author:steve tag:rust"));
+}
+
+#[test]
+fn default_operator() {
+    let notes = notes();
+    let mut db = open_db(&notes.db_path()).expect("Failed to open database");
+    let mut joined_by = |op: MatchOp, qstr: &str| -> Vec<String> {
+        let query = parse_user_query_with_flags(qstr, "en", DEFAULT_QUERY_FLAGS, op, None)
+            .expect("Failed to parse");
+        let mut found: Vec<String> = query_db(&mut db, query, SortOrder::Relevance, 0)
+            .expect("Failed to query")
+            .matches
+            .into_iter()
+            .map(|m| m.doc.filename)
+            .collect();
+        found.sort();
+        found
+    };
+    // Only the lighthouse note has a keeper as well as a lamp
+    assert_eq!(
+        vec!["lighthouses.md", "rust.md"],
+        joined_by(MatchOp::Or, "keeper lamp")
+    );
+    assert_eq!(
+        vec!["lighthouses.md"],
+        joined_by(MatchOp::And, "keeper lamp")
+    );
+    assert!(joined_by(MatchOp::And, "keeper tomatoes").is_empty());
+}
+
+#[test]
+fn phrase() {
+    let notes = notes();
+    assert_eq!(vec!["lighthouses.md"], notes.query(r#""trimmed the lamp""#));
+    assert!(notes.query(r#""lamp the trimmed""#).is_empty());
+}
+
+#[test]
+fn boolean_operators() {
+    let notes = notes();
+    assert_eq!(vec!["lighthouses.md"], notes.query("lamp AND tag:history"));
+    assert_eq!(
+        vec!["garden.md", "rust.md"],
+        notes.query("tag:garden OR tag:rust")
+    );
+    assert_eq!(
+        vec!["lighthouses.md", "rust.md"],
+        notes.query("author:steve")
+    );
+    assert_eq!(
+        vec!["rust.md"],
+        notes.query("author:steve AND NOT tag:travel")
+    );
+}
+
+#[test]
+fn quoted_field_values() {
+    let notes = notes();
+    assert_eq!(
+        vec!["lighthouses.md", "rust.md"],
+        notes.query(r#"author:"Steve Sosik""#)
+    );
+    assert!(notes.query(r#"author:"Sosik Steve""#).is_empty());
+    assert_eq!(vec!["rust.md"], notes.query(r#"title:'rust notes'"#));
+}
+
+#[test]
+fn title_only() {
+    let notes = notes();
+    let mut db = open_db(&notes.db_path()).expect("Failed to open database");
+    let mut titled = |qstr: &str| -> Vec<String> {
+        let query =
+            title_query(qstr, "en", DEFAULT_QUERY_FLAGS, Some(&mut db)).expect("Failed to parse");
+        query_db(&mut db, query, SortOrder::Relevance, 0)
+            .expect("Failed to query")
+            .matches
+            .into_iter()
+            .map(|m| m.doc.filename)
+            .collect()
+    };
+    assert_eq!(vec!["lighthouses.md"], titled("lighthouses"));
+    assert_eq!(vec!["rust.md"], titled("notes"));
+    // Only in the body, which a full query does match
+    assert!(titled("keeper").is_empty());
+    assert_eq!(vec!["lighthouses.md"], notes.query("keeper"));
+}